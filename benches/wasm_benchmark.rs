@@ -3,18 +3,34 @@ use brainfk_rs::Language;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use std::fs;
 use std::path::PathBuf;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use std::process::{Command, Stdio};
 use std::time::Duration;
 
 pub fn bench_helper(input_file: &PathBuf) -> Language {
     let mut brainfk = Language::new(input_file, true);
     brainfk.parse().unwrap();
+    brainfk.optimize();
     brainfk.generate_wasm(&Target::Wasi).unwrap();
     brainfk.validate().unwrap();
-    brainfk.optimize();
     brainfk.compile_wasmu(&Backend::LLVM).unwrap();
     brainfk
 }
 
+/// Compile to a native executable in the temp dir, returning its path
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub fn native_helper(input_file: &PathBuf) -> PathBuf {
+    let mut brainfk = Language::new(input_file, true);
+    brainfk.parse().unwrap();
+    brainfk.optimize();
+    brainfk.generate_native().unwrap();
+    let output_file = std::env::temp_dir().join(input_file.file_stem().unwrap());
+    brainfk
+        .write_native(output_file.as_os_str().to_str().unwrap())
+        .unwrap();
+    output_file
+}
+
 pub fn criterion_benchmark(c: &mut Criterion) {
     // List all .bf files in files
     let mut tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -34,14 +50,44 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     group.sample_size(15);
     group.warm_up_time(Duration::from_secs(60));
 
-    for bf_file in bf_files {
-        let bf_module = bench_helper(&bf_file);
+    for bf_file in &bf_files {
+        let bf_module = bench_helper(bf_file);
         group.bench_with_input(
             BenchmarkId::new("Mandelbrot", bf_file.file_name().unwrap().to_str().unwrap()),
             &bf_module,
-            |b, _bf_module| b.iter(|| bf_module.run()),
+            |b, _bf_module| b.iter(|| bf_module.run().unwrap()),
+        );
+    }
+    group.finish();
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    native_benchmark(c, &bf_files);
+}
+
+/// Same programs compiled without the wasm layer, executables are only
+/// generated for x86-64 Linux
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn native_benchmark(c: &mut Criterion, bf_files: &[PathBuf]) {
+    let mut group = c.benchmark_group("Native_Bench");
+    group.sample_size(15);
+    group.warm_up_time(Duration::from_secs(60));
+
+    for bf_file in bf_files {
+        let executable = native_helper(bf_file);
+        group.bench_with_input(
+            BenchmarkId::new("Mandelbrot", bf_file.file_name().unwrap().to_str().unwrap()),
+            &executable,
+            |b, executable| {
+                b.iter(|| {
+                    Command::new(executable)
+                        .stdout(Stdio::null())
+                        .status()
+                        .unwrap()
+                })
+            },
         );
     }
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
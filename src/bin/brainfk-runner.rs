@@ -0,0 +1,117 @@
+//! Runner the binaries from `compile-binary` are a copy of
+//!
+//! A binary is this executable followed by a serialized module, its length
+//! and `EMBEDDED_MAGIC`, see `Language::compile_binary`. Only the headless
+//! engine is used, the module is compiled already.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process;
+use wasmer::{Instance, Module, RuntimeError, Store, Universal};
+use wasmer_wasi::{Stdin, Stdout, WasiState};
+
+/// Marks the end of a binary carrying a serialized wasmer module
+const EMBEDDED_MAGIC: &[u8; 8] = b"BRAINFK\0";
+
+/// Failure to load or run the module, with the exit codes of brainfk-rs
+enum Failure {
+    Io(io::Error),
+    Compile(String),
+    Instantiation(String),
+    Runtime(RuntimeError),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Io(_) => 1,
+            Failure::Compile(_) => 7,
+            Failure::Instantiation(_) => 8,
+            Failure::Runtime(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Io(e) => write!(f, "{e}"),
+            Failure::Compile(message) => write!(f, "Compile error: {message}"),
+            Failure::Instantiation(message) => write!(f, "Instantiation error: {message}"),
+            Failure::Runtime(e) => write!(f, "Runtime error: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        Failure::Io(e)
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {e}");
+        process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), Failure> {
+    let wasmu = embedded_module(&env::current_exe()?)?.ok_or_else(|| {
+        Failure::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No module is embedded, binaries are made with brainfk-rs compile-binary",
+        ))
+    })?;
+    let store = Store::new(&Universal::headless().engine());
+    // Safe as the module was serialized by `compile_binary` with this executable as runner
+    let module = unsafe { Module::deserialize(&store, wasmu) }
+        .map_err(|e| Failure::Compile(e.to_string()))?;
+
+    let mut wasi_env = WasiState::new("brainfk")
+        .stdin(Box::new(Stdin))
+        .stdout(Box::new(Stdout))
+        .finalize()
+        .map_err(|e| Failure::Instantiation(e.to_string()))?;
+    let import_object = wasi_env
+        .import_object(&module)
+        .map_err(|e| Failure::Instantiation(e.to_string()))?;
+    let instance = Instance::new(&module, &import_object)
+        .map_err(|e| Failure::Instantiation(e.to_string()))?;
+    let start = instance
+        .exports
+        .get_function("_start")
+        .map_err(|e| Failure::Instantiation(e.to_string()))?;
+    start.call(&[]).map_err(Failure::Runtime)?;
+    Ok(())
+}
+
+/// Serialized module carried by an executable if it is a compiled binary
+fn embedded_module(executable: &Path) -> Result<Option<Vec<u8>>, Failure> {
+    let mut binary = fs::File::open(executable)?;
+    let len = binary.metadata()?.len();
+    if len < 16 {
+        return Ok(None);
+    }
+
+    // Trailer of module length and magic
+    let mut trailer = [0; 16];
+    binary.seek(SeekFrom::Start(len - 16))?;
+    binary.read_exact(&mut trailer)?;
+    if &trailer[8..] != EMBEDDED_MAGIC {
+        return Ok(None);
+    }
+    let wasmu_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    if wasmu_len > len - 16 {
+        return Ok(None);
+    }
+
+    let mut wasmu = vec![0; wasmu_len as usize];
+    binary.seek(SeekFrom::Start(len - 16 - wasmu_len))?;
+    binary.read_exact(&mut wasmu)?;
+    Ok(Some(wasmu))
+}
@@ -0,0 +1,159 @@
+use crate::cmd::{CellWidth, EofMode, TapeMode};
+use crate::ir::{Instr, Op};
+use std::fmt::Write;
+
+/// Settings of the C program, matching those of the wasm module
+pub(crate) struct Config {
+    pub(crate) cell_width: CellWidth,
+    pub(crate) eof_mode: EofMode,
+    pub(crate) tape: TapeMode,
+    pub(crate) tape_length: u32,
+    pub(crate) tape_start: u32,
+    /// Longest a growable tape can grow to
+    pub(crate) max_length: Option<u64>,
+    /// Report the tape header moving left of the first cell
+    pub(crate) checked: bool,
+}
+
+/// Generate a portable C program with the same behavior as the wasm module
+pub(crate) fn source(program: &[Instr], config: &Config, input_name: &str) -> String {
+    let mut c = String::new();
+    writeln!(c, "/* Generated by brainfk-rs from {input_name} */").unwrap();
+    c.push_str(
+        "#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n\n",
+    );
+    let cell = match config.cell_width {
+        CellWidth::Bits8 => "uint8_t",
+        CellWidth::Bits16 => "uint16_t",
+        CellWidth::Bits32 => "uint32_t",
+        CellWidth::Bits64 => "uint64_t",
+    };
+    writeln!(c, "typedef {cell} cell;\n").unwrap();
+    writeln!(c, "static cell *tape;").unwrap();
+    writeln!(c, "static long length = {};", config.tape_length).unwrap();
+    c.push_str("/* Index of the cell at the tape header */\nstatic long h;\n\n");
+    c.push_str(PRELUDE);
+    index_of(&mut c, config);
+
+    c.push_str("\nint main(void) {\n");
+    c.push_str("    tape = calloc(length, sizeof(cell));\n");
+    c.push_str("    if (!tape) {\n        return 1;\n    }\n");
+    writeln!(c, "    h = {};", config.tape_start).unwrap();
+    ops(&mut c, program, config, 1);
+    c.push_str("    return 0;\n}\n");
+    c
+}
+
+/// Functions which do not depend on the settings, inline so the unused ones
+/// do not warn
+const PRELUDE: &str = r#"/* Report the tape header moving off the tape, like the wasm module */
+static inline void tape_error(int left, unsigned long instruction) {
+    fflush(stdout);
+    fprintf(stderr, "tape %s at instruction %lu\n", left ? "underflow" : "overflow", instruction);
+    exit(1);
+}
+
+/* Multiply in 64 bits so narrow cells do not overflow int */
+static inline cell mul(cell a, cell b) {
+    return (cell)((uint64_t)a * b);
+}
+
+/* Write a cell as little endian bytes of its full width */
+static inline void output(cell value) {
+    size_t i;
+    for (i = 0; i < sizeof(cell); i++) {
+        putchar((int)((uint64_t)value >> (8 * i) & 0xff));
+    }
+}
+"#;
+
+/// Emit `index_of`, turning the index of a cell the instruction is about to
+/// access into a valid one according to the tape mode
+fn index_of(c: &mut String, config: &Config) {
+    c.push_str("\nstatic inline long index_of(long i, unsigned long instruction) {\n");
+    if config.checked && config.tape != TapeMode::Wrap {
+        c.push_str("    if (i < 0) {\n        tape_error(1, instruction);\n    }\n");
+    }
+    match config.tape {
+        TapeMode::Growable => {
+            c.push_str("    if (i >= length) {\n");
+            c.push_str("        long new_length = length * 2 > i ? length * 2 : i + 1;\n");
+            if let Some(max_length) = config.max_length {
+                writeln!(c, "        if (new_length > {max_length}) {{").unwrap();
+                writeln!(c, "            new_length = {max_length};").unwrap();
+                c.push_str("        }\n");
+                c.push_str("        if (i >= new_length) {\n");
+                c.push_str("            tape_error(0, instruction);\n");
+                c.push_str("        }\n");
+            }
+            c.push_str("        tape = realloc(tape, new_length * sizeof(cell));\n");
+            c.push_str(
+                "        if (!tape) {\n            tape_error(0, instruction);\n        }\n",
+            );
+            c.push_str("        memset(tape + length, 0, (new_length - length) * sizeof(cell));\n");
+            c.push_str("        length = new_length;\n");
+            c.push_str("    }\n");
+        }
+        TapeMode::Fixed => {
+            c.push_str("    if (i >= length) {\n        tape_error(0, instruction);\n    }\n");
+        }
+        TapeMode::Wrap => {
+            c.push_str("    i %= length;\n");
+            c.push_str("    if (i < 0) {\n        i += length;\n    }\n");
+            c.push_str("    (void)instruction;\n");
+        }
+    }
+    c.push_str("    return i;\n}\n");
+}
+
+/// Emit statements for a sequence of operations at an indentation depth
+fn ops(c: &mut String, ops: &[Instr], config: &Config, depth: usize) {
+    let indent = "    ".repeat(depth);
+    for Instr { op, index, .. } in ops {
+        // Instructions count from 1
+        let instruction = index + 1;
+        match op {
+            Op::Add(n) => writeln!(c, "{indent}tape[h] += (cell){n};").unwrap(),
+            Op::Move(n) => writeln!(c, "{indent}h = index_of(h + {n}, {instruction});").unwrap(),
+            Op::Clear => writeln!(c, "{indent}tape[h] = 0;").unwrap(),
+            Op::MulAdd { offset, factor } => writeln!(
+                c,
+                "{indent}if (tape[h]) {{\n\
+                 {indent}    long i = index_of(h + {offset}, {instruction});\n\
+                 {indent}    tape[i] += mul(tape[h], (cell){factor});\n\
+                 {indent}}}"
+            )
+            .unwrap(),
+            Op::Scan(n) => writeln!(
+                c,
+                "{indent}while (tape[h]) {{\n\
+                 {indent}    h = index_of(h + {n}, {instruction});\n\
+                 {indent}}}"
+            )
+            .unwrap(),
+            Op::Input => {
+                writeln!(c, "{indent}{{\n{indent}    int byte = getchar();").unwrap();
+                writeln!(c, "{indent}    if (byte != EOF) {{").unwrap();
+                writeln!(c, "{indent}        tape[h] = (cell)byte;").unwrap();
+                match config.eof_mode {
+                    EofMode::Zero => {
+                        writeln!(c, "{indent}    }} else {{\n{indent}        tape[h] = 0;").unwrap()
+                    }
+                    EofMode::MinusOne => writeln!(
+                        c,
+                        "{indent}    }} else {{\n{indent}        tape[h] = (cell)-1;"
+                    )
+                    .unwrap(),
+                    EofMode::Unchanged => {}
+                }
+                writeln!(c, "{indent}    }}\n{indent}}}").unwrap();
+            }
+            Op::Output => writeln!(c, "{indent}output(tape[h]);").unwrap(),
+            Op::Loop(body) => {
+                writeln!(c, "{indent}while (tape[h]) {{").unwrap();
+                self::ops(c, body, config, depth + 1);
+                writeln!(c, "{indent}}}").unwrap();
+            }
+        }
+    }
+}
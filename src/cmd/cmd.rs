@@ -1,10 +1,9 @@
-use std::path::PathBuf;
-
-use crate::Language;
-use clap::{Parser, Subcommand};
+use crate::{Error, Language};
+use clap::{Args, CommandFactory, ErrorKind, Parser, Subcommand};
+use std::env;
 
 pub trait Run {
-    fn run(&self) -> Result<(), std::io::Error>;
+    fn run(&self) -> Result<(), Error>;
 }
 
 #[derive(Parser, Debug)]
@@ -26,6 +25,27 @@ pub enum Command {
         output_file: String,
         #[clap(long, value_parser = clap::value_parser!(Target))]
         target: Target,
+        /// Also write an ES module and TypeScript declarations loading the
+        /// wasm file, browser target only
+        #[clap(long, action)]
+        glue: bool,
+        /// Binary module or text format annotated with source positions,
+        /// text is written before wasm-opt runs
+        #[clap(long, value_parser = clap::value_parser!(Format), default_value = "wasm")]
+        format: Format,
+        #[clap(flatten)]
+        options: CodegenOptions,
+    },
+    /// Generate C source
+    GenerateC {
+        /// .b/.BF file for generating C
+        #[clap(value_parser)]
+        input_file: String,
+        /// Output file name
+        #[clap(value_parser)]
+        output_file: String,
+        #[clap(flatten)]
+        options: CodegenOptions,
     },
     /// Compile to wasmu
     CompileWasmu {
@@ -38,6 +58,8 @@ pub enum Command {
         /// Backend to use for compiling
         #[clap(long, value_parser = clap::value_parser!(Backend))]
         backend: Backend,
+        #[clap(flatten)]
+        options: CodegenOptions,
     },
     /// Compile to binary
     CompileBinary {
@@ -50,6 +72,19 @@ pub enum Command {
         /// Backend to use for compiling
         #[clap(long, value_parser = clap::value_parser!(Backend))]
         backend: Backend,
+        #[clap(flatten)]
+        options: CodegenOptions,
+    },
+    /// Compile to a native x86-64 Linux executable without wasm
+    CompileNative {
+        /// .b/.BF file for compiling
+        #[clap(value_parser)]
+        input_file: String,
+        /// Output file name
+        #[clap(value_parser)]
+        output_file: String,
+        #[clap(flatten)]
+        options: NativeOptions,
     },
     /// Run
     Run {
@@ -59,9 +94,71 @@ pub enum Command {
         /// Backend to use for compiling
         #[clap(long, value_parser = clap::value_parser!(Backend))]
         backend: Backend,
+        #[clap(flatten)]
+        options: CodegenOptions,
     },
 }
 
+/// Settings of every subcommand generating code through wasm or C
+#[derive(Args, Debug)]
+pub struct CodegenOptions {
+    #[clap(flatten)]
+    shared: NativeOptions,
+    /// Trap with a diagnostic when the tape header moves off the tape,
+    /// loops optimized away from -O2 are reported at their `[`
+    #[clap(long, action)]
+    checked: bool,
+    /// Maximum number of 64KiB memory pages a growable tape can grow to
+    #[clap(long, value_parser)]
+    max_pages: Option<u32>,
+    /// Whether the tape grows, is fixed or wraps around at its length
+    #[clap(long, value_parser = clap::value_parser!(TapeMode), default_value = "growable")]
+    tape: TapeMode,
+}
+
+impl CodegenOptions {
+    /// Configure the language with the settings of every backend and the
+    /// tape settings native executables lack
+    pub fn apply(&self, brainfk: &mut Language) {
+        self.shared.apply(brainfk);
+        brainfk.checked = self.checked;
+        brainfk.max_pages = self.max_pages;
+        brainfk.tape_mode = self.tape;
+    }
+}
+
+/// Settings every backend takes, the only ones of native executables as
+/// their tape is always fixed and unchecked
+#[derive(Args, Debug)]
+pub struct NativeOptions {
+    /// Optimization level
+    #[clap(short = 'O', long, value_parser = clap::value_parser!(OptLevel), default_value = "2")]
+    opt_level: OptLevel,
+    /// Cell width in bits
+    #[clap(long, value_parser = clap::value_parser!(CellWidth), default_value = "32")]
+    cell_width: CellWidth,
+    /// Value stored by `,` at end of input
+    #[clap(long, value_parser = clap::value_parser!(EofMode), default_value = "unchanged")]
+    eof: EofMode,
+    /// Number of cells on the tape, the initial length of a growable tape
+    #[clap(long, value_parser, default_value = "30000")]
+    tape_length: u32,
+    /// Cell the tape header starts at
+    #[clap(long, value_parser, default_value = "0")]
+    tape_start: u32,
+}
+
+impl NativeOptions {
+    /// Configure the language with the settings
+    pub fn apply(&self, brainfk: &mut Language) {
+        brainfk.opt_level = self.opt_level;
+        brainfk.cell_width = self.cell_width;
+        brainfk.eof_mode = self.eof;
+        brainfk.tape_length = self.tape_length;
+        brainfk.tape_start = self.tape_start;
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
 pub enum Target {
     /// Allows running in a browser
@@ -70,6 +167,62 @@ pub enum Target {
     Wasi,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Binary module
+    Wasm,
+    /// Text format
+    Wat,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OptLevel {
+    /// No optimizations
+    #[clap(name = "0")]
+    O0,
+    /// Fold runs of arithmetic and pointer moves
+    #[clap(name = "1")]
+    O1,
+    /// Also recognize loop idioms and remove dead loops
+    #[clap(name = "2")]
+    O2,
+    /// Also run wasm-opt, downloaded on first use
+    #[clap(name = "3")]
+    O3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CellWidth {
+    #[clap(name = "8")]
+    Bits8,
+    #[clap(name = "16")]
+    Bits16,
+    #[clap(name = "32")]
+    Bits32,
+    #[clap(name = "64")]
+    Bits64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EofMode {
+    /// Store 0
+    Zero,
+    /// Store -1, which is 255 in 8 bit cells
+    MinusOne,
+    /// Leave the cell unchanged
+    Unchanged,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TapeMode {
+    /// Grow memory when the tape header moves past the end
+    Growable,
+    /// Report a tape overflow when the tape header moves past the end
+    Fixed,
+    /// Wrap around to the other end when the tape header moves past either end
+    Wrap,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
 pub enum Backend {
     Cranelift,
@@ -78,56 +231,95 @@ pub enum Backend {
 }
 
 impl Run for Cli {
-    fn run(&self) -> Result<(), std::io::Error> {
+    fn run(&self) -> Result<(), Error> {
         self.command.run()
     }
 }
 
 impl Run for Command {
-    fn run(&self) -> Result<(), std::io::Error> {
+    fn run(&self) -> Result<(), Error> {
         match self {
             Command::Run {
                 input_file,
                 backend,
+                options,
             } => {
-                let input_path = PathBuf::from(input_file);
-                let mut brainfk = Language::new(&input_path, false);
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
                 brainfk.parse()?;
-                brainfk.generate_wasm(&Target::Wasi)?; // Only WASI can run natively
-                brainfk.validate().unwrap();
                 brainfk.optimize();
-                brainfk.compile_wasmu(&backend).unwrap();
-                brainfk.run();
-                Ok(())
+                brainfk.generate_wasm(&Target::Wasi)?; // Only WASI can run natively
+                brainfk.validate()?;
+                brainfk.optimize_wasm()?;
+                brainfk.compile_wasmu(&backend)?;
+                brainfk.run()
             }
             Command::GenerateWasm {
                 input_file,
                 output_file,
                 target,
+                glue,
+                format,
+                options,
             } => {
-                let input_path = PathBuf::from(input_file);
-                let mut brainfk = Language::new(&input_path, false);
+                // Usage error, as the arguments can't express it
+                if *glue && (*target != Target::Browser || *format != Format::Wasm) {
+                    Cli::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--glue is only generated for --target browser with --format wasm",
+                        )
+                        .exit();
+                }
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
+                brainfk.emit_wat = *format == Format::Wat;
                 brainfk.parse()?;
-                brainfk.generate_wasm(&target)?;
-                brainfk.validate().unwrap();
                 brainfk.optimize();
+                brainfk.generate_wasm(&target)?;
+                brainfk.validate()?;
+                if let Format::Wat = format {
+                    brainfk.write_wat(output_file)?;
+                    println!("✔ Successfully generated wat.");
+                    return Ok(());
+                }
+                brainfk.optimize_wasm()?;
                 brainfk.write_wasm(&output_file)?;
+                if *glue {
+                    brainfk.write_glue(output_file)?;
+                }
                 println!("✔ Successfully generated wasm.");
                 Ok(())
             }
+            Command::GenerateC {
+                input_file,
+                output_file,
+                options,
+            } => {
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
+                brainfk.parse()?;
+                brainfk.optimize();
+                brainfk.generate_c()?;
+                brainfk.write_c(output_file)?;
+                println!("✔ Successfully generated C.");
+                Ok(())
+            }
             Command::CompileWasmu {
                 input_file,
                 output_file,
                 backend,
+                options,
             } => {
-                let input_path = PathBuf::from(input_file);
-                let mut brainfk = Language::new(&input_path, false);
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
                 brainfk.parse()?;
-                brainfk.generate_wasm(&Target::Wasi)?;
-                brainfk.validate().unwrap();
                 brainfk.optimize();
-                brainfk.compile_wasmu(&backend).unwrap();
-                brainfk.write_wasmu(output_file).unwrap();
+                brainfk.generate_wasm(&Target::Wasi)?;
+                brainfk.validate()?;
+                brainfk.optimize_wasm()?;
+                brainfk.compile_wasmu(&backend)?;
+                brainfk.write_wasmu(output_file)?;
                 println!("✔ Compiled successfully to wasmu.");
                 println!("Compiled file can be executed using wasmer-headless.");
                 Ok(())
@@ -136,14 +328,34 @@ impl Run for Command {
                 input_file,
                 output_file,
                 backend,
+                options,
             } => {
-                let input_path = PathBuf::from(input_file);
-                let mut brainfk = Language::new(&input_path, false);
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
                 brainfk.parse()?;
+                brainfk.optimize();
                 brainfk.generate_wasm(&Target::Wasi)?; // Only WASI can generate a binary
-                brainfk.validate().unwrap();
+                brainfk.validate()?;
+                brainfk.optimize_wasm()?;
+                // Installed and built next to this executable
+                let runner = env::current_exe()?
+                    .with_file_name(format!("brainfk-runner{}", env::consts::EXE_SUFFIX));
+                brainfk.compile_binary(&output_file, &runner, &backend)?;
+                println!("✔ Compiled successfully to binary.");
+                Ok(())
+            }
+            Command::CompileNative {
+                input_file,
+                output_file,
+                options,
+            } => {
+                let mut brainfk = Language::new(input_file, false);
+                options.apply(&mut brainfk);
+                brainfk.parse()?;
                 brainfk.optimize();
-                brainfk.compile_binary(&output_file, &backend).unwrap();
+                brainfk.generate_native()?;
+                brainfk.write_native(output_file)?;
+                println!("✔ Compiled successfully to native executable.");
                 Ok(())
             }
         }
@@ -1,96 +1,555 @@
+use crate::cmd::{CellWidth, EofMode, TapeMode};
+use crate::ir::{Instr, Op};
+use crate::wat::Body;
 use crate::Target;
-use wasm_encoder::{BlockType, Function, Instruction, MemArg};
+use wasm_encoder::{BlockType, Instruction, MemArg, ValType};
 
-pub(crate) fn less_than(f: &mut Function) {
-    // Move tape header left by 4 bytes
+/// Address of the first cell of the tape
+pub(crate) const TAPE_START: i32 = 2048;
+/// Index of the function reporting tape errors
+pub(crate) const TAPE_ERROR: u32 = 3;
+/// Index of the function growing memory for the tape
+pub(crate) const GROW_TAPE: u32 = 4;
+/// Module of the functions browser targets import
+pub(crate) const BROWSER_MODULE: &str = "env";
+/// Function returning the next byte of input or -1 at end of input
+pub(crate) const BROWSER_READ: &str = "read";
+/// Function writing a range of memory to output
+pub(crate) const BROWSER_WRITE: &str = "write";
+
+/// Settings which shape the generated code
+pub(crate) struct Config {
+    pub(crate) target: Target,
+    pub(crate) cell_width: CellWidth,
+    pub(crate) eof_mode: EofMode,
+    pub(crate) tape: TapeMode,
+    pub(crate) tape_length: u32,
+    /// Trap when the tape header moves off the tape
+    pub(crate) checked: bool,
+}
+
+/// Emit code for a sequence of operations
+///
+/// Pointer moves are tracked at compile time and folded into the offset of
+/// the cell accesses, the tape header is only updated at loop boundaries,
+/// I/O and the end of the sequence.
+///
+/// Memory is grown whenever the tape header moves past its end, unless the
+/// tape is fixed in which case it is reported as a tape overflow. A wrapping
+/// tape moves the tape header right away as every access has to wrap.
+pub(crate) fn ops(f: &mut Body, ops: &[Instr], config: &Config) {
+    let cell = &config.cell_width;
+    // Cells moved since the tape header was last updated
+    let mut offset = 0;
+    // Furthest cell known to be in memory since the tape header was last updated
+    let mut grown = 0;
+    for instr @ Instr { op, index, .. } in ops {
+        if matches!(op, Op::Scan(_) | Op::Input | Op::Output | Op::Loop(_)) {
+            shift(f, offset, config);
+            offset = 0;
+            grown = 0;
+        }
+        match op {
+            Op::Add(n) if *n > 0 => plus(f, offset, *n, cell),
+            Op::Add(n) => minus(f, offset, n.wrapping_neg(), cell),
+            Op::Move(n) if config.tape == TapeMode::Wrap => shift(f, *n, config),
+            Op::Move(n) => {
+                offset += n;
+                if offset > grown {
+                    end_check(f, offset, *index, config);
+                    grown = offset;
+                }
+                if config.checked && *n < 0 {
+                    underflow_check(f, offset, *index, cell);
+                }
+            }
+            Op::Clear => clear(f, offset, cell),
+            Op::MulAdd { offset: to, factor } if config.tape == TapeMode::Wrap => {
+                wrapped_mul_add(f, *to, *factor, config)
+            }
+            Op::MulAdd { offset: to, factor } => {
+                let target = offset + to;
+                // The loop never reached other cells if the cell was zero
+                let guard = target > grown || (config.checked && *to < 0);
+                if guard {
+                    let mem_arg = address(f, offset, cell);
+                    f.instruction(&cell.load(mem_arg));
+                    f.instruction(&cell.eqz());
+                    f.instruction(&Instruction::I32Eqz);
+                    f.instruction(&Instruction::If(BlockType::Empty));
+                    if target > grown {
+                        end_check(f, target, *index, config);
+                    }
+                    if config.checked && *to < 0 {
+                        underflow_check(f, target, *index, cell);
+                    }
+                }
+                mul_add(f, offset, target, *factor, cell);
+                if guard {
+                    f.instruction(&Instruction::End);
+                }
+            }
+            Op::Scan(n) => {
+                source_comment(f, "scan", instr);
+                scan(f, *n, *index, config);
+            }
+            Op::Input => {
+                source_comment(f, "input", instr);
+                comma(f, &config.target, cell, &config.eof_mode);
+            }
+            Op::Output => {
+                source_comment(f, "output", instr);
+                dot(f, &config.target, cell);
+            }
+            Op::Loop(body) => {
+                source_comment(f, "loop", instr);
+                sq_start(f, cell);
+                self::ops(f, body, config);
+                sq_end(f, cell);
+                source_comment(f, "end of loop", instr);
+            }
+        }
+    }
+    shift(f, offset, config);
+}
+
+/// Comment mapping the code of an operation back to the source
+fn source_comment(f: &mut Body, what: &str, instr: &Instr) {
+    let Instr {
+        index, position, ..
+    } = instr;
+    // Instructions count from 1
+    f.comment(format_args!(
+        "{what} at instruction {} (line {}, column {})",
+        index + 1,
+        position.line,
+        position.column
+    ));
+}
+
+impl CellWidth {
+    /// Size of a cell in bytes
+    pub(crate) fn bytes(&self) -> i32 {
+        match self {
+            CellWidth::Bits8 => 1,
+            CellWidth::Bits16 => 2,
+            CellWidth::Bits32 => 4,
+            CellWidth::Bits64 => 8,
+        }
+    }
+
+    /// Load a cell, narrow cells are zero extended
+    fn load(&self, mem_arg: MemArg) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits8 => Instruction::I32Load8_U(mem_arg),
+            CellWidth::Bits16 => Instruction::I32Load16_U(mem_arg),
+            CellWidth::Bits32 => Instruction::I32Load(mem_arg),
+            CellWidth::Bits64 => Instruction::I64Load(mem_arg),
+        }
+    }
+
+    /// Store a cell, narrow cells wrap around by truncation
+    fn store(&self, mem_arg: MemArg) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits8 => Instruction::I32Store8(mem_arg),
+            CellWidth::Bits16 => Instruction::I32Store16(mem_arg),
+            CellWidth::Bits32 => Instruction::I32Store(mem_arg),
+            CellWidth::Bits64 => Instruction::I64Store(mem_arg),
+        }
+    }
+
+    fn constant(&self, n: i32) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Const(n as i64),
+            _ => Instruction::I32Const(n),
+        }
+    }
+
+    fn add(&self) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Add,
+            _ => Instruction::I32Add,
+        }
+    }
+
+    fn sub(&self) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Sub,
+            _ => Instruction::I32Sub,
+        }
+    }
+
+    fn mul(&self) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Mul,
+            _ => Instruction::I32Mul,
+        }
+    }
+
+    fn eqz(&self) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Eqz,
+            _ => Instruction::I32Eqz,
+        }
+    }
+
+    fn ne(&self) -> Instruction<'static> {
+        match self {
+            CellWidth::Bits64 => Instruction::I64Ne,
+            _ => Instruction::I32Ne,
+        }
+    }
+}
+
+/// Push the address of the cell `offset` cells away from the tape header,
+/// returns the memory argument to access it with
+fn address(f: &mut Body, offset: i32, cell: &CellWidth) -> MemArg {
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Const(4));
-    f.instruction(&Instruction::I32Sub);
-    f.instruction(&Instruction::LocalSet(0));
+    // Memory argument offsets are unsigned
+    if offset < 0 {
+        f.instruction(&Instruction::I32Const(offset * cell.bytes()));
+        f.instruction(&Instruction::I32Add);
+    }
+    MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: (offset.max(0) * cell.bytes()) as u64,
+    }
 }
 
-pub(crate) fn greater_than(f: &mut Function) {
-    // Move tape header right by 4 bytes
+/// Report a tape underflow for the instruction at `index` if the cell `offset`
+/// cells away from the tape header is before the start of the tape
+fn underflow_check(f: &mut Body, offset: i32, index: usize, cell: &CellWidth) {
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Const(4));
+    f.instruction(&Instruction::I32Const(offset * cell.bytes()));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(0));
+    f.instruction(&Instruction::I32Const(TAPE_START));
+    f.instruction(&Instruction::I32LtS);
+    f.instruction(&Instruction::If(BlockType::Empty));
+    f.instruction(&Instruction::I32Const(1)); // Underflow
+    f.instruction(&Instruction::I32Const(index as i32 + 1)); // Instructions count from 1
+    f.instruction(&Instruction::LocalGet(1)); // Pending output
+    f.instruction(&Instruction::Call(TAPE_ERROR));
+    f.instruction(&Instruction::End);
 }
 
-pub(crate) fn plus(f: &mut Function) {
-    // Increment value at tape header by 1
+/// Grow memory or report a tape overflow for the instruction at `index` if the
+/// cell `offset` cells away from the tape header is past the end of the tape
+fn end_check(f: &mut Body, offset: i32, index: usize, config: &Config) {
+    let cell = &config.cell_width;
+    // End of the cell
     f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const((offset + 1) * cell.bytes()));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalGet(6));
+    f.instruction(&Instruction::I32GtU);
+    f.instruction(&Instruction::If(BlockType::Empty));
+    match config.tape {
+        TapeMode::Growable => {
+            f.instruction(&Instruction::LocalGet(0));
+            f.instruction(&Instruction::I32Const((offset + 1) * cell.bytes()));
+            f.instruction(&Instruction::I32Add);
+            f.instruction(&Instruction::I32Const(index as i32 + 1)); // Instructions count from 1
+            f.instruction(&Instruction::LocalGet(1)); // Pending output
+            f.instruction(&Instruction::Call(GROW_TAPE));
+            f.instruction(&Instruction::LocalSet(6));
+        }
+        TapeMode::Fixed => {
+            f.instruction(&Instruction::I32Const(0)); // Overflow
+            f.instruction(&Instruction::I32Const(index as i32 + 1)); // Instructions count from 1
+            f.instruction(&Instruction::LocalGet(1)); // Pending output
+            f.instruction(&Instruction::Call(TAPE_ERROR));
+        }
+        TapeMode::Wrap => unreachable!("A wrapping tape has no end"),
+    }
+    f.instruction(&Instruction::End);
+}
+
+/// Move tape header by n cells
+pub(crate) fn shift(f: &mut Body, n: i32, config: &Config) {
+    match n {
+        0 => {}
+        n if n > 0 => greater_than(f, n, config),
+        n => less_than(f, -n, config),
+    }
+}
+
+pub(crate) fn less_than(f: &mut Body, n: i32, config: &Config) {
+    // Move tape header left by n cells
+    let cell = &config.cell_width;
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Load(MemArg {
+    if config.tape == TapeMode::Wrap {
+        let n = n % config.tape_length as i32;
+        f.instruction(&Instruction::I32Const(n * cell.bytes()));
+        f.instruction(&Instruction::I32Sub);
+        wrap(f, false, config);
+    } else {
+        f.instruction(&Instruction::I32Const(n * cell.bytes()));
+        f.instruction(&Instruction::I32Sub);
+    }
+    f.instruction(&Instruction::LocalSet(0));
+}
+
+pub(crate) fn greater_than(f: &mut Body, n: i32, config: &Config) {
+    // Move tape header right by n cells
+    let cell = &config.cell_width;
+    f.instruction(&Instruction::LocalGet(0));
+    if config.tape == TapeMode::Wrap {
+        let n = n % config.tape_length as i32;
+        f.instruction(&Instruction::I32Const(n * cell.bytes()));
+        f.instruction(&Instruction::I32Add);
+        wrap(f, true, config);
+    } else {
+        f.instruction(&Instruction::I32Const(n * cell.bytes()));
+        f.instruction(&Instruction::I32Add);
+    }
+    f.instruction(&Instruction::LocalSet(0));
+}
+
+/// Wrap the address on the stack, which is less than a tape length past the
+/// `right` or left end of a wrapping tape, back onto the tape
+fn wrap(f: &mut Body, right: bool, config: &Config) {
+    let size = config.tape_length as i32 * config.cell_width.bytes();
+    if config.tape_length.is_power_of_two() {
+        // Mask the offset into the tape
+        f.instruction(&Instruction::I32Const(TAPE_START));
+        f.instruction(&Instruction::I32Sub);
+        f.instruction(&Instruction::I32Const(size - 1));
+        f.instruction(&Instruction::I32And);
+        f.instruction(&Instruction::I32Const(TAPE_START));
+        f.instruction(&Instruction::I32Add);
+    } else if right {
+        // Select the address a tape length to the left if past the end
+        f.instruction(&Instruction::LocalTee(7));
+        f.instruction(&Instruction::I32Const(size));
+        f.instruction(&Instruction::I32Sub);
+        f.instruction(&Instruction::LocalGet(7));
+        f.instruction(&Instruction::LocalGet(7));
+        f.instruction(&Instruction::I32Const(TAPE_START + size));
+        f.instruction(&Instruction::I32GeU);
+        f.instruction(&Instruction::Select);
+    } else {
+        // Select the address a tape length to the right if before the start
+        f.instruction(&Instruction::LocalTee(7));
+        f.instruction(&Instruction::I32Const(size));
+        f.instruction(&Instruction::I32Add);
+        f.instruction(&Instruction::LocalGet(7));
+        f.instruction(&Instruction::LocalGet(7));
+        f.instruction(&Instruction::I32Const(TAPE_START));
+        f.instruction(&Instruction::I32LtS);
+        f.instruction(&Instruction::Select);
+    }
+}
+
+pub(crate) fn plus(f: &mut Body, offset: i32, n: i32, cell: &CellWidth) {
+    // Increment value offset cells from tape header by n
+    let mem_arg = address(f, offset, cell);
+    address(f, offset, cell);
+    f.instruction(&cell.load(mem_arg));
+    f.instruction(&cell.constant(n));
+    f.instruction(&cell.add());
+    f.instruction(&cell.store(mem_arg));
+}
+
+pub(crate) fn minus(f: &mut Body, offset: i32, n: i32, cell: &CellWidth) {
+    // Decrement value offset cells from tape header by n
+    let mem_arg = address(f, offset, cell);
+    address(f, offset, cell);
+    f.instruction(&cell.load(mem_arg));
+    f.instruction(&cell.constant(n));
+    f.instruction(&cell.sub());
+    f.instruction(&cell.store(mem_arg));
+}
+
+pub(crate) fn clear(f: &mut Body, offset: i32, cell: &CellWidth) {
+    // Set value offset cells from tape header to 0
+    let mem_arg = address(f, offset, cell);
+    f.instruction(&cell.constant(0));
+    f.instruction(&cell.store(mem_arg));
+}
+
+pub(crate) fn mul_add(f: &mut Body, from: i32, to: i32, factor: i32, cell: &CellWidth) {
+    // Add value at cell `from` times factor to the value at cell `to`
+    let to_arg = address(f, to, cell);
+    address(f, to, cell);
+    f.instruction(&cell.load(to_arg));
+    let from_arg = address(f, from, cell);
+    f.instruction(&cell.load(from_arg));
+    if factor != 1 {
+        f.instruction(&cell.constant(factor));
+        f.instruction(&cell.mul());
+    }
+    f.instruction(&cell.add());
+    f.instruction(&cell.store(to_arg));
+}
+
+/// Add value at the tape header times factor to the value `to` cells away on
+/// a wrapping tape
+pub(crate) fn wrapped_mul_add(f: &mut Body, to: i32, factor: i32, config: &Config) {
+    let cell = &config.cell_width;
+    let mem_arg = MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
-    }));
-    f.instruction(&Instruction::I32Const(1));
+    };
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(
+        to.rem_euclid(config.tape_length as i32) * cell.bytes(),
+    ));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::I32Store(MemArg {
+    wrap(f, true, config);
+    f.instruction(&Instruction::LocalSet(7));
+    f.instruction(&Instruction::LocalGet(7));
+    f.instruction(&Instruction::LocalGet(7));
+    f.instruction(&cell.load(mem_arg));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&cell.load(mem_arg));
+    if factor != 1 {
+        f.instruction(&cell.constant(factor));
+        f.instruction(&cell.mul());
+    }
+    f.instruction(&cell.add());
+    f.instruction(&cell.store(mem_arg));
+}
+
+pub(crate) fn scan(f: &mut Body, n: i32, index: usize, config: &Config) {
+    let cell = &config.cell_width;
+    let wide = n == 1 && *cell == CellWidth::Bits8 && config.tape != TapeMode::Wrap;
+    if wide {
+        f.instruction(&Instruction::Block(BlockType::Empty));
+        wide_scan(f);
+    }
+    // Move tape header by n cells until value at tape header is 0
+    f.instruction(&Instruction::Block(BlockType::Empty));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&cell.load(MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
     }));
+    f.instruction(&cell.eqz());
+    f.instruction(&Instruction::BrIf(1));
+    shift(f, n, config);
+    match config.tape {
+        TapeMode::Wrap => {}
+        _ if n > 0 => end_check(f, 0, index, config),
+        _ if config.checked => underflow_check(f, 0, index, cell),
+        _ => {}
+    }
+    f.instruction(&Instruction::Br(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+    if wide {
+        f.instruction(&Instruction::End);
+    }
 }
 
-pub(crate) fn minus(f: &mut Function) {
-    // Decrement value at tape header by 1
+/// Scan right over 8 bit cells eight at a time while they are on the tape,
+/// branching out of the enclosing block once the zero cell is found and
+/// falling through to the scan of single cells near the end of the tape
+fn wide_scan(f: &mut Body) {
+    let mem_arg = MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    };
+    f.instruction(&Instruction::Block(BlockType::Empty));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(8));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalGet(6));
+    f.instruction(&Instruction::I32GtU);
+    f.instruction(&Instruction::BrIf(1));
+    // High bit of each zero byte, of which the lowest is never a false positive
+    // (x - 0x01..01) & !x & 0x80..80
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(mem_arg));
+    f.instruction(&Instruction::LocalTee(8));
+    f.instruction(&Instruction::I64Const(0x0101_0101_0101_0101));
+    f.instruction(&Instruction::I64Sub);
+    f.instruction(&Instruction::LocalGet(8));
+    f.instruction(&Instruction::I64Const(-1));
+    f.instruction(&Instruction::I64Xor);
+    f.instruction(&Instruction::I64And);
+    f.instruction(&Instruction::I64Const(0x8080_8080_8080_8080_u64 as i64));
+    f.instruction(&Instruction::I64And);
+    f.instruction(&Instruction::LocalTee(8));
+    f.instruction(&Instruction::I64Eqz);
+    f.instruction(&Instruction::If(BlockType::Empty));
+    // No zero cell among the eight
     f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(8));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(0));
+    f.instruction(&Instruction::Br(1));
+    f.instruction(&Instruction::End);
+    // Move to the first zero cell and skip the single cell scan
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(8));
+    f.instruction(&Instruction::I64Ctz);
+    f.instruction(&Instruction::I32WrapI64);
+    f.instruction(&Instruction::I32Const(3));
+    f.instruction(&Instruction::I32ShrU);
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(0));
+    f.instruction(&Instruction::Br(2));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+}
+
+/// Output the cell at the data pointer
+pub(crate) fn dot(f: &mut Body, target: &crate::Target, cell: &CellWidth) {
+    f.instruction(&Instruction::LocalGet(1));
+
+    // Get cell from tape
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Load(MemArg {
+    f.instruction(&cell.load(MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
     }));
-    f.instruction(&Instruction::I32Const(1));
-    f.instruction(&Instruction::I32Sub);
-    f.instruction(&Instruction::I32Store(MemArg {
+
+    // Write cell to buffer
+    f.instruction(&cell.store(MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
     }));
+
+    // Increment buffer counter
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(cell.bytes()));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(1));
+
+    // Check if buffer needs to be flushed
+    // Start Block
+    f.instruction(&Instruction::Block(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalGet(2));
+    f.instruction(&Instruction::I32LtS);
+    f.instruction(&Instruction::BrIf(0));
+    // Flush Buffer
+    flush_stdout(f, target);
+    // Reset buffer pointer to start of buffer
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::LocalSet(1));
+
+    // Block End
+    f.instruction(&Instruction::End);
 }
 
-/// Output the byte at the data pointer
-pub(crate) fn dot(f: &mut Function, target: &crate::Target) {
+/// Flush I/O Buffer
+pub(crate) fn flush_stdout(f: &mut Body, target: &crate::Target) {
     match target {
         Target::Browser => {
-            todo!();
+            f.instruction(&Instruction::I32Const(0)); // Buffer starts at 0th bit
+            f.instruction(&Instruction::LocalGet(1)); // length of filled buffer
+            f.instruction(&Instruction::Call(1)); // Call write
         }
         Target::Wasi => {
-            f.instruction(&Instruction::LocalGet(1));
-
-            // Get byte from tape
-            f.instruction(&Instruction::LocalGet(0));
-            f.instruction(&Instruction::I32Load(MemArg {
-                align: 0,
-                memory_index: 0,
-                offset: 0,
-            }));
-
-            // Write byte to buffer
-            f.instruction(&Instruction::I32Store(MemArg {
-                align: 0,
-                memory_index: 0,
-                offset: 0,
-            }));
-
-            // Increment buffer counter
-            f.instruction(&Instruction::LocalGet(1));
-            f.instruction(&Instruction::I32Const(4));
-            f.instruction(&Instruction::I32Add);
-            f.instruction(&Instruction::LocalSet(1));
-
-            // Check if buffer needs to be flushed
-            // Start Block
-            f.instruction(&Instruction::Block(BlockType::Empty));
-            f.instruction(&Instruction::LocalGet(1));
-            f.instruction(&Instruction::LocalGet(2));
-            f.instruction(&Instruction::I32LtS);
-            f.instruction(&Instruction::BrIf(0));
-            // Flush Buffer
             // Write IO Vector
             // [ start_pos, total_len ]
             f.instruction(&Instruction::LocalGet(3)); // I/O Vector start point
@@ -118,120 +577,359 @@ pub(crate) fn dot(f: &mut Function, target: &crate::Target) {
             f.instruction(&Instruction::I32Add);
             f.instruction(&Instruction::Call(1)); // Call fd_write
             f.instruction(&Instruction::Drop);
-            // Reset buffer pointer to start of buffer
-            f.instruction(&Instruction::I32Const(0));
-            f.instruction(&Instruction::LocalSet(1));
-
-            // Block End
-            f.instruction(&Instruction::End);
         }
     };
 }
 
-/// Flush I/O Buffer
-pub(crate) fn flush_stdout(f: &mut Function, target: &crate::Target) {
+/// Accept one byte of input, storing its value in the cell at the data pointer
+pub(crate) fn comma(f: &mut Body, target: &crate::Target, cell: &CellWidth, eof: &EofMode) {
     match target {
         Target::Browser => {
-            todo!();
+            // The host buffers input and hands it out a byte at a time
+            f.instruction(&Instruction::Call(0)); // Call read
+            f.instruction(&Instruction::LocalTee(4));
+            f.instruction(&Instruction::I32Const(0));
+            f.instruction(&Instruction::I32GeS);
+            f.instruction(&Instruction::If(BlockType::Empty));
+            // Copy input byte to tape
+            f.instruction(&Instruction::LocalGet(0));
+            f.instruction(&Instruction::LocalGet(4));
+            if let CellWidth::Bits64 = cell {
+                f.instruction(&Instruction::I64ExtendI32U);
+            }
+            f.instruction(&cell.store(MemArg {
+                align: 0,
+                memory_index: 0,
+                offset: 0,
+            }));
+            // Negative means end of input
+            eof_else(f, cell, eof);
+            f.instruction(&Instruction::End);
         }
         Target::Wasi => {
+            // Refill Input Buffer once all of it is consumed
+            // Start Block
+            f.instruction(&Instruction::LocalGet(4));
+            f.instruction(&Instruction::LocalGet(5));
+            f.instruction(&Instruction::I32Eq);
+            f.instruction(&Instruction::If(BlockType::Empty));
             // Write IO Vector
             // [ start_pos, total_len ]
             f.instruction(&Instruction::LocalGet(3)); // I/O Vector start point
-            f.instruction(&Instruction::I32Const(0)); // Buffer starts at 0th bit
+            f.instruction(&Instruction::I32Const(1024)); // Input Buffer start
             f.instruction(&Instruction::I32Store(MemArg {
                 align: 0,
                 memory_index: 0,
                 offset: 0,
             }));
-
             f.instruction(&Instruction::LocalGet(3));
             f.instruction(&Instruction::I32Const(4));
             f.instruction(&Instruction::I32Add);
-            f.instruction(&Instruction::LocalGet(1)); // length of filled buffer
+            f.instruction(&Instruction::I32Const(1024)); // Input Buffer size
             f.instruction(&Instruction::I32Store(MemArg {
                 align: 0,
                 memory_index: 0,
                 offset: 0,
             }));
-            f.instruction(&Instruction::I32Const(1)); // FD: Stdout
+            // Nothing is read unless fd_read says so
+            f.instruction(&Instruction::LocalGet(3));
+            f.instruction(&Instruction::I32Const(8));
+            f.instruction(&Instruction::I32Add);
+            f.instruction(&Instruction::I32Const(0));
+            f.instruction(&Instruction::I32Store(MemArg {
+                align: 0,
+                memory_index: 0,
+                offset: 0,
+            }));
+            f.instruction(&Instruction::I32Const(0)); // FD: Stdin
             f.instruction(&Instruction::LocalGet(3)); // *iovs: 0
             f.instruction(&Instruction::I32Const(1)); // iovs_len
-            f.instruction(&Instruction::LocalGet(3)); // nbytes writen
+            f.instruction(&Instruction::LocalGet(3)); // nbytes read
             f.instruction(&Instruction::I32Const(8));
             f.instruction(&Instruction::I32Add);
-            f.instruction(&Instruction::Call(1)); // Call fd_write
+            f.instruction(&Instruction::Call(0)); // Call fd_read
             f.instruction(&Instruction::Drop);
-        }
-    };
-}
+            // Reset buffer pointers to the bytes read
+            f.instruction(&Instruction::I32Const(1024));
+            f.instruction(&Instruction::LocalSet(4));
+            f.instruction(&Instruction::I32Const(1024));
+            f.instruction(&Instruction::LocalGet(3));
+            f.instruction(&Instruction::I32Load(MemArg {
+                align: 0,
+                memory_index: 0,
+                offset: 8,
+            }));
+            f.instruction(&Instruction::I32Add);
+            f.instruction(&Instruction::LocalSet(5));
+            // Block End
+            f.instruction(&Instruction::End);
 
-/// Accept one byte of input, storing its value in the byte at the data pointer
-pub(crate) fn comma(f: &mut Function, target: &crate::Target) {
-    match target {
-        Target::Browser => {
-            todo!();
-        }
-        Target::Wasi => {
-            // Write IO Vector
-            f.instruction(&Instruction::LocalGet(3)); // I/O Vector start point
+            // Nothing read means end of input
+            f.instruction(&Instruction::LocalGet(4));
+            f.instruction(&Instruction::LocalGet(5));
+            f.instruction(&Instruction::I32Ne);
+            f.instruction(&Instruction::If(BlockType::Empty));
+            // Copy input byte to tape
             f.instruction(&Instruction::LocalGet(0));
-            f.instruction(&Instruction::I32Store(MemArg {
+            f.instruction(&Instruction::LocalGet(4));
+            f.instruction(&Instruction::I32Load8_U(MemArg {
                 align: 0,
                 memory_index: 0,
                 offset: 0,
             }));
-            f.instruction(&Instruction::LocalGet(3));
-            f.instruction(&Instruction::I32Const(4));
-            f.instruction(&Instruction::I32Add);
-            f.instruction(&Instruction::I32Const(1));
-            f.instruction(&Instruction::I32Store(MemArg {
+            if let CellWidth::Bits64 = cell {
+                f.instruction(&Instruction::I64ExtendI32U);
+            }
+            f.instruction(&cell.store(MemArg {
                 align: 0,
                 memory_index: 0,
                 offset: 0,
             }));
-            f.instruction(&Instruction::I32Const(0)); // FD: Stdin
-            f.instruction(&Instruction::LocalGet(3)); // *iovs: 0
-            f.instruction(&Instruction::I32Const(1)); // iovs_len
-            f.instruction(&Instruction::LocalGet(3)); // nbytes writen
-            f.instruction(&Instruction::I32Const(8));
+            // Increment buffer pointer
+            f.instruction(&Instruction::LocalGet(4));
+            f.instruction(&Instruction::I32Const(1));
             f.instruction(&Instruction::I32Add);
-            f.instruction(&Instruction::Call(0)); // Call fd_read
-            f.instruction(&Instruction::Drop);
+            f.instruction(&Instruction::LocalSet(4));
+            // End of input
+            eof_else(f, cell, eof);
+            f.instruction(&Instruction::End);
         }
     }
 }
 
+/// Else branch of reading input storing the end of input value, if any
+fn eof_else(f: &mut Body, cell: &CellWidth, eof: &EofMode) {
+    let eof_value = match eof {
+        EofMode::Zero => Some(0),
+        EofMode::MinusOne => Some(-1),
+        EofMode::Unchanged => None,
+    };
+    if let Some(value) = eof_value {
+        f.instruction(&Instruction::Else);
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&cell.constant(value));
+        f.instruction(&cell.store(MemArg {
+            align: 0,
+            memory_index: 0,
+            offset: 0,
+        }));
+    }
+}
+
 /// Loop Start
-pub(crate) fn sq_start(f: &mut Function, _sig: u32) {
+pub(crate) fn sq_start(f: &mut Body, cell: &CellWidth) {
     // Skip the loop if condition not satisfied
     f.instruction(&Instruction::Block(BlockType::Empty));
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Load(MemArg {
+    f.instruction(&cell.load(MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
     }));
-    f.instruction(&Instruction::I32Eqz);
+    f.instruction(&cell.eqz());
     f.instruction(&Instruction::BrIf(0));
     // Start Do..While loop
     f.instruction(&Instruction::Loop(BlockType::Empty));
 }
 
 /// Loop Stop
-pub(crate) fn sq_end(f: &mut Function) {
+pub(crate) fn sq_end(f: &mut Body, cell: &CellWidth) {
     // Branch to loop on neqz
     f.instruction(&Instruction::LocalGet(0));
-    f.instruction(&Instruction::I32Load(MemArg {
+    f.instruction(&cell.load(MemArg {
         align: 0,
         memory_index: 0,
         offset: 0,
     }));
-    f.instruction(&Instruction::I32Const(0));
-    f.instruction(&Instruction::I32Ne);
+    f.instruction(&cell.constant(0));
+    f.instruction(&cell.ne());
     f.instruction(&Instruction::BrIf(0));
     // End Do..While Loop
     f.instruction(&Instruction::End);
     // End Block
     f.instruction(&Instruction::End);
 }
+
+/// Function growing memory to fit the tape
+///
+/// Takes the address memory has to reach, the instruction moving the tape
+/// header and the pending output to report a tape overflow with if memory
+/// can not grow, returns the new end of memory.
+pub(crate) fn grow_tape(with_text: bool) -> Body {
+    // Local 0 : I32 Required end of memory
+    // Local 1 : I32 Instruction
+    // Local 2 : I32 Pending output length
+    let mut f = Body::new(vec![], with_text);
+
+    // Grow by the missing pages
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(0xffff));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::I32Const(16));
+    f.instruction(&Instruction::I32ShrU);
+    f.instruction(&Instruction::MemorySize(0));
+    f.instruction(&Instruction::I32Sub);
+    f.instruction(&Instruction::MemoryGrow(0));
+    f.instruction(&Instruction::I32Const(-1));
+    f.instruction(&Instruction::I32Eq);
+    f.instruction(&Instruction::If(BlockType::Empty));
+    f.instruction(&Instruction::I32Const(0)); // Overflow
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalGet(2));
+    f.instruction(&Instruction::Call(TAPE_ERROR));
+    f.instruction(&Instruction::End);
+
+    f.instruction(&Instruction::MemorySize(0));
+    f.instruction(&Instruction::I32Const(16));
+    f.instruction(&Instruction::I32Shl);
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// Function reporting the tape header moving off the tape
+///
+/// Takes whether it moved off the left end, the instruction moving it and
+/// the pending output which is flushed before the diagnostic is written to
+/// stderr and execution traps. Browsers have no stderr, so only the trap is
+/// left for the host to report.
+pub(crate) fn tape_error(target: &Target, with_text: bool) -> Body {
+    // Local 0 : I32 Off the left end
+    // Local 1 : I32 Instruction
+    // Local 2 : I32 Pending output length
+    // Local 3 : I32 Message pointer
+    // Local 4 : I32 Message end
+    // Local 5 : I32 Remaining digits
+    let mut f = Body::new(vec![(3, ValType::I32)], with_text);
+
+    if let Target::Browser = target {
+        // Flush Stdout
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::LocalGet(2));
+        f.instruction(&Instruction::Call(1)); // Call write
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+        return f;
+    }
+
+    // Flush Stdout
+    f.instruction(&Instruction::I32Const(1004));
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Store(MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    }));
+    f.instruction(&Instruction::I32Const(1008));
+    f.instruction(&Instruction::LocalGet(2));
+    f.instruction(&Instruction::I32Store(MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    }));
+    f.instruction(&Instruction::I32Const(1)); // FD: Stdout
+    f.instruction(&Instruction::I32Const(1004)); // *iovs
+    f.instruction(&Instruction::I32Const(1)); // iovs_len
+    f.instruction(&Instruction::I32Const(1012)); // nbytes writen
+    f.instruction(&Instruction::Call(1)); // Call fd_write
+    f.instruction(&Instruction::Drop);
+
+    // Write message to the now empty I/O Buffer
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::LocalSet(3));
+    write_str(&mut f, "tape ");
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::If(BlockType::Empty));
+    write_str(&mut f, "underflow");
+    f.instruction(&Instruction::Else);
+    write_str(&mut f, "overflow");
+    f.instruction(&Instruction::End);
+    write_str(&mut f, " at instruction ");
+
+    // Skip over the digits of the instruction
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalSet(5));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::I32Const(1));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(3));
+    f.instruction(&Instruction::LocalGet(5));
+    f.instruction(&Instruction::I32Const(10));
+    f.instruction(&Instruction::I32DivU);
+    f.instruction(&Instruction::LocalTee(5));
+    f.instruction(&Instruction::BrIf(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::LocalSet(4));
+
+    // Write the digits backwards
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalSet(5));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::I32Const(1));
+    f.instruction(&Instruction::I32Sub);
+    f.instruction(&Instruction::LocalTee(3));
+    f.instruction(&Instruction::LocalGet(5));
+    f.instruction(&Instruction::I32Const(10));
+    f.instruction(&Instruction::I32RemU);
+    f.instruction(&Instruction::I32Const(b'0' as i32));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::I32Store8(MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    }));
+    f.instruction(&Instruction::LocalGet(5));
+    f.instruction(&Instruction::I32Const(10));
+    f.instruction(&Instruction::I32DivU);
+    f.instruction(&Instruction::LocalTee(5));
+    f.instruction(&Instruction::BrIf(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::LocalGet(4));
+    f.instruction(&Instruction::LocalSet(3));
+    write_str(&mut f, "\n");
+
+    // Write message to Stderr
+    f.instruction(&Instruction::I32Const(1004));
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Store(MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    }));
+    f.instruction(&Instruction::I32Const(1008));
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::I32Store(MemArg {
+        align: 0,
+        memory_index: 0,
+        offset: 0,
+    }));
+    f.instruction(&Instruction::I32Const(2)); // FD: Stderr
+    f.instruction(&Instruction::I32Const(1004)); // *iovs
+    f.instruction(&Instruction::I32Const(1)); // iovs_len
+    f.instruction(&Instruction::I32Const(1012)); // nbytes writen
+    f.instruction(&Instruction::Call(1)); // Call fd_write
+    f.instruction(&Instruction::Drop);
+
+    f.instruction(&Instruction::Unreachable);
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// Write a string at the message pointer of `tape_error` and advance it
+fn write_str(f: &mut Body, s: &str) {
+    for (i, byte) in s.bytes().enumerate() {
+        f.instruction(&Instruction::LocalGet(3));
+        f.instruction(&Instruction::I32Const(byte as i32));
+        f.instruction(&Instruction::I32Store8(MemArg {
+            align: 0,
+            memory_index: 0,
+            offset: i as u64,
+        }));
+    }
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::I32Const(s.len() as i32));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(3));
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::io;
+
+/// Failure at any stage of compiling or running brainfk code
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the code or writing generated files failed
+    Io(io::Error),
+    /// The code is not valid brainfk, at a line and column counting from 1
+    Parse {
+        message: String,
+        line: usize,
+        column: usize,
+        /// Source line the error is on
+        snippet: String,
+    },
+    /// The settings do not allow generating code
+    Codegen(String),
+    /// The generated module is not valid wasm
+    Validation(wasmparser::BinaryReaderError),
+    /// wasm-opt could not be downloaded or run
+    Optimization(String),
+    /// The engine could not compile, serialize or load the module
+    Compile(String),
+    /// WASI could not be set up or the module could not be instantiated
+    Instantiation(String),
+    /// Execution trapped, for example on a checked tape error
+    Runtime(wasmer::RuntimeError),
+}
+
+impl Error {
+    /// Exit code telling the failing stage apart, 2 is left for usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Parse { .. } => 3,
+            Error::Codegen(_) => 4,
+            Error::Validation(_) => 5,
+            Error::Optimization(_) => 6,
+            Error::Compile(_) => 7,
+            Error::Instantiation(_) => 8,
+            Error::Runtime(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Parse {
+                message,
+                line,
+                column,
+                snippet,
+            } => {
+                // Caret under the column, keeping tabs so it lines up
+                let margin = " ".repeat(line.to_string().len());
+                let caret: String = snippet
+                    .chars()
+                    .take(column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                writeln!(f, "{message} at line {line}, column {column}")?;
+                writeln!(f, "{margin} |")?;
+                writeln!(f, "{line} | {snippet}")?;
+                write!(f, "{margin} | {caret}^")
+            }
+            Error::Codegen(message) => write!(f, "Code generation error: {message}"),
+            Error::Validation(e) => write!(f, "Invalid wasm generated: {e}"),
+            Error::Optimization(message) => write!(f, "wasm-opt failed: {message}"),
+            Error::Compile(message) => write!(f, "Compile error: {message}"),
+            Error::Instantiation(message) => write!(f, "Instantiation error: {message}"),
+            Error::Runtime(e) => write!(f, "Runtime error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Validation(e) => Some(e),
+            Error::Runtime(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<wasmparser::BinaryReaderError> for Error {
+    fn from(e: wasmparser::BinaryReaderError) -> Self {
+        Error::Validation(e)
+    }
+}
+
+impl From<wasmer::RuntimeError> for Error {
+    fn from(e: wasmer::RuntimeError) -> Self {
+        Error::Runtime(e)
+    }
+}
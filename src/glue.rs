@@ -0,0 +1,79 @@
+use crate::compiler::{BROWSER_MODULE, BROWSER_READ, BROWSER_WRITE};
+
+/// ES module loading a browser targeted wasm file, `__NAME__` placeholders
+/// are filled in by `javascript`
+const JAVASCRIPT: &str = r#"// Generated by brainfk-rs, runs __WASM__
+const wasmUrl = new URL("./__WASM__", import.meta.url);
+let compiled;
+
+// Compile the module once, reading it from disk outside of browsers
+function compile() {
+  compiled ??=
+    wasmUrl.protocol === "file:"
+      ? import("node:fs/promises")
+          .then((fs) => fs.readFile(wasmUrl))
+          .then((bytes) => WebAssembly.compile(bytes))
+      : WebAssembly.compileStreaming(fetch(wasmUrl));
+  return compiled;
+}
+
+export async function runStreaming({ read, write }) {
+  let memory;
+  const instance = await WebAssembly.instantiate(await compile(), {
+    "__MODULE__": {
+      "__READ__": () => read(),
+      // Memory may have grown since the last write, so view it afresh
+      "__WRITE__": (ptr, len) => write(new Uint8Array(memory.buffer, ptr, len).slice()),
+    },
+  });
+  memory = instance.exports.memory;
+  instance.exports._start();
+}
+
+export async function run(input = new Uint8Array()) {
+  const chunks = [];
+  let pos = 0;
+  await runStreaming({
+    read: () => (pos < input.length ? input[pos++] : -1),
+    write: (chunk) => chunks.push(chunk),
+  });
+  const output = new Uint8Array(chunks.reduce((len, chunk) => len + chunk.length, 0));
+  let offset = 0;
+  for (const chunk of chunks) {
+    output.set(chunk, offset);
+    offset += chunk.length;
+  }
+  return output;
+}
+"#;
+
+/// TypeScript declarations of the ES module, `__JS__` is filled in by
+/// `typescript`
+const TYPESCRIPT: &str = r#"// Generated by brainfk-rs, types of __JS__
+export interface Callbacks {
+  /** Next byte of input, or -1 at end of input */
+  read(): number;
+  /** Receives output as it is flushed, each cell is written in full */
+  write(chunk: Uint8Array): void;
+}
+
+/** Run the program, streaming input and output through callbacks */
+export function runStreaming(callbacks: Callbacks): Promise<void>;
+
+/** Run the program on the whole input, resolving to the whole output */
+export function run(input?: Uint8Array): Promise<Uint8Array>;
+"#;
+
+/// ES module running the wasm file `wasm_name` next to it
+pub(crate) fn javascript(wasm_name: &str) -> String {
+    JAVASCRIPT
+        .replace("__WASM__", wasm_name)
+        .replace("__MODULE__", BROWSER_MODULE)
+        .replace("__READ__", BROWSER_READ)
+        .replace("__WRITE__", BROWSER_WRITE)
+}
+
+/// TypeScript declarations of the ES module `js_name`
+pub(crate) fn typescript(js_name: &str) -> String {
+    TYPESCRIPT.replace("__JS__", js_name)
+}
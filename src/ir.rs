@@ -0,0 +1,163 @@
+use crate::Error;
+
+/// Operations of the intermediate representation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Add a value to the cell at the tape header
+    Add(i32),
+    /// Move the tape header by a number of cells
+    Move(i32),
+    /// Set the cell at the tape header to zero
+    Clear,
+    /// Add the cell at the tape header times `factor` to the cell `offset` cells away
+    MulAdd { offset: i32, factor: i32 },
+    /// Move the tape header by a number of cells until it reaches a zero cell
+    Scan(i32),
+    /// Repeat the body while the cell at the tape header is non-zero
+    Loop(Vec<Instr>),
+    /// Read one byte of input into the cell at the tape header
+    Input,
+    /// Write the cell at the tape header to output
+    Output,
+}
+
+/// Where a symbol is in the source
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// Line counting from 1
+    pub line: usize,
+    /// Column in characters counting from 1
+    pub column: usize,
+}
+
+/// An operation with the position it was parsed from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instr {
+    pub op: Op,
+    /// Index of the first brainfk symbol of the operation
+    pub index: usize,
+    /// Position of the first brainfk symbol of the operation in the source
+    pub position: Position,
+}
+
+/// Build the intermediate representation from brainfk source, anything but
+/// the eight symbols is a comment
+pub fn parse(source: &str) -> Result<Vec<Instr>, Error> {
+    // Stack of loop bodies with the index and position of their `[`,
+    // the bottom one is the program itself
+    let mut blocks: Vec<(usize, Position, Vec<Instr>)> = vec![(0, Position::default(), Vec::new())];
+    let mut index = 0;
+    let (mut line, mut column) = (1, 0);
+    for (offset, symb) in source.char_indices() {
+        column += 1;
+        let position = Position {
+            offset,
+            line,
+            column,
+        };
+        let op = match symb {
+            '+' => Op::Add(1),
+            '-' => Op::Add(-1),
+            '>' => Op::Move(1),
+            '<' => Op::Move(-1),
+            ',' => Op::Input,
+            '.' => Op::Output,
+            '[' => {
+                blocks.push((index, position, Vec::new()));
+                index += 1;
+                continue;
+            }
+            ']' => {
+                if blocks.len() == 1 {
+                    return Err(unmatched(source, ']', position));
+                }
+                let (start, position, body) = blocks.pop().unwrap();
+                blocks.last_mut().unwrap().2.push(Instr {
+                    op: Op::Loop(body),
+                    index: start,
+                    position,
+                });
+                index += 1;
+                continue;
+            }
+            '\n' => {
+                line += 1;
+                column = 0;
+                continue;
+            }
+            _ => continue,
+        };
+        blocks.last_mut().unwrap().2.push(Instr {
+            op,
+            index,
+            position,
+        });
+        index += 1;
+    }
+    if blocks.len() != 1 {
+        // Report the innermost `[` which is left open
+        let (_, position, _) = blocks.pop().unwrap();
+        return Err(unmatched(source, '[', position));
+    }
+    Ok(blocks.pop().unwrap().2)
+}
+
+/// Error for an unmatched bracket at a position of the source
+fn unmatched(source: &str, bracket: char, position: Position) -> Error {
+    let Position { line, column, .. } = position;
+    let snippet = source.lines().nth(line - 1).unwrap_or_default();
+    Error::Parse {
+        message: format!("Unmatched '{bracket}'"),
+        line,
+        column,
+        snippet: snippet.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_positions() -> Result<(), Error> {
+        // Comments count towards offsets and columns but not instructions
+        let program = parse("é+\n  [-]")?;
+        let positions: Vec<(usize, Position)> =
+            program.iter().map(|i| (i.index, i.position)).collect();
+        assert_eq!(
+            positions,
+            [
+                (
+                    0,
+                    Position {
+                        offset: 2,
+                        line: 1,
+                        column: 2
+                    }
+                ),
+                (
+                    1,
+                    Position {
+                        offset: 6,
+                        line: 2,
+                        column: 3
+                    }
+                ),
+            ]
+        );
+        match &program[1].op {
+            Op::Loop(body) => assert_eq!(
+                body[0].position,
+                Position {
+                    offset: 7,
+                    line: 2,
+                    column: 4
+                }
+            ),
+            op => panic!("expected a loop, found {op:?}"),
+        }
+        Ok(())
+    }
+}
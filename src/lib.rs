@@ -1,124 +1,241 @@
+mod c;
 pub mod cmd;
 mod compiler;
+mod error;
+mod glue;
+pub mod ir;
+mod native;
+pub mod passes;
+mod wat;
 
-use crate::cmd::{Backend, Target};
-use std::collections::HashSet;
+pub use crate::error::Error;
+
+use crate::cmd::{Backend, CellWidth, EofMode, OptLevel, TapeMode, Target};
+use crate::ir::Instr;
 use std::env::temp_dir;
 use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use uuid::Uuid;
 use wasm_encoder::EntityType;
 use wasm_encoder::ExportSection;
 use wasm_encoder::{
-    CodeSection, Function, FunctionSection, ImportSection, Instruction, MemorySection, MemoryType,
-    Module, TypeSection, ValType,
+    CodeSection, FunctionSection, ImportSection, Instruction, MemorySection, MemoryType, Module,
+    TypeSection, ValType,
 };
 use wasm_pack::PBAR;
 use wasm_pack::{cache, wasm_opt};
-use wasmer::CompileError;
 use wasmer::Module as WasmerModule;
 use wasmer::{Cranelift, Instance, Singlepass, Store, Universal, LLVM};
 use wasmer_wasi::{Pipe, Stdin, Stdout, WasiState};
 
-pub struct Language<'a> {
-    /// This contains the characterset from brainfk language
-    pub char_set: HashSet<char>,
-    /// Character by language symbols
-    pub code: Vec<char>,
+/// Marks the end of a binary carrying a serialized wasmer module, as read by
+/// brainfk-runner
+const EMBEDDED_MAGIC: &[u8; 8] = b"BRAINFK\0";
+
+/// Where the code is read from
+pub enum Source {
+    /// Read the file when parsing
+    File(PathBuf),
+    /// Code held in memory
+    Code(String),
+}
+
+pub struct Language {
+    /// Parsed program in intermediate representation
+    pub program: Vec<Instr>,
     /// Generated bytecode
     pub wasm_bytes: Vec<u8>,
+    /// Generated module in text format, before any wasm-opt pass, left empty
+    /// unless `emit_wat` is set
+    pub wat: String,
+    /// Also generate the module in text format with the bytecode
+    pub emit_wat: bool,
+    /// Generated native executable
+    pub native_bytes: Vec<u8>,
+    /// Generated C source
+    pub c_source: String,
     /// Wasmer module
     pub module: Option<wasmer::Module>,
+    /// Optimization level
+    pub opt_level: OptLevel,
+    /// Width of a tape cell
+    pub cell_width: CellWidth,
+    /// Behavior of `,` at end of input
+    pub eof_mode: EofMode,
+    /// Trap with a diagnostic when the tape header moves off the tape
+    pub checked: bool,
+    /// Maximum number of memory pages a growable tape can grow to
+    pub max_pages: Option<u32>,
+    /// Number of cells on the tape, the initial length of a growable tape
+    pub tape_length: u32,
+    /// Cell the tape header starts at
+    pub tape_start: u32,
+    /// Whether the tape grows, is fixed or wraps around at its length
+    pub tape_mode: TapeMode,
     /// Suppress I/O streams (useful for benching)
     pub suppress_io: bool,
-    /// Input file name
-    pub input_file: &'a PathBuf,
+    /// Code to parse
+    pub source: Source,
 }
 
-impl<'a> Language<'a> {
-    pub fn new(input_file: &'a PathBuf, suppress_io: bool) -> Self {
+impl Language {
+    /// Compile the code in a file, read when parsing
+    pub fn new(input_file: impl Into<PathBuf>, suppress_io: bool) -> Self {
+        Self::with_source(Source::File(input_file.into()), suppress_io)
+    }
+
+    /// Compile code held in memory, such as code received over an API
+    pub fn from_source(code: &str) -> Self {
+        Self::with_source(Source::Code(code.to_string()), false)
+    }
+
+    /// Compile code read to the end of a reader
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, Error> {
+        let mut code = String::new();
+        reader.read_to_string(&mut code)?;
+        Ok(Self::with_source(Source::Code(code), false))
+    }
+
+    fn with_source(source: Source, suppress_io: bool) -> Self {
         Self {
-            char_set: HashSet::from(['<', '>', '+', '-', '.', ',', '[', ']']),
-            code: Vec::new(),
+            program: Vec::new(),
             wasm_bytes: Vec::new(),
+            wat: String::new(),
+            emit_wat: false,
+            native_bytes: Vec::new(),
+            c_source: String::new(),
             module: None,
+            opt_level: OptLevel::O2,
+            cell_width: CellWidth::Bits32,
+            eof_mode: EofMode::Unchanged,
+            checked: false,
+            max_pages: None,
+            tape_length: 30000,
+            tape_start: 0,
+            tape_mode: TapeMode::Growable,
             suppress_io,
-            input_file,
+            source,
         }
     }
 
-    /// Open and parse the code
-    pub fn parse(&mut self) -> Result<(), io::Error> {
-        let path = std::path::Path::new(&self.input_file);
-        let content = fs::read_to_string(path)?;
-        self.code = content
-            .chars()
-            .filter(|x| self.char_set.contains(x))
-            .collect();
+    /// Read the code if it is in a file and parse it
+    pub fn parse(&mut self) -> Result<(), Error> {
+        self.program = match &self.source {
+            Source::File(path) => ir::parse(&fs::read_to_string(path)?)?,
+            Source::Code(code) => ir::parse(code)?,
+        };
         Ok(())
     }
 
+    /// Check the tape header starts on the tape and the tape placed at `base`
+    /// fits in memory addressed by 32 bit integers, returning where it ends
+    fn check_tape_start(&self, base: u64) -> Result<u64, Error> {
+        if self.tape_start >= self.tape_length {
+            return Err(Error::Codegen(format!(
+                "Tape start {} is not within the {} cells of the tape",
+                self.tape_start, self.tape_length
+            )));
+        }
+        let tape_end = base + self.tape_length as u64 * self.cell_width.bytes() as u64;
+        if tape_end > i32::MAX as u64 {
+            return Err(Error::Codegen(format!(
+                "Tape of {} cells does not fit in memory",
+                self.tape_length
+            )));
+        }
+        Ok(tape_end)
+    }
+
     /// Generate WASM bytecode
-    pub fn generate_wasm(&mut self, target: &Target) -> Result<(), io::Error> {
+    pub fn generate_wasm(&mut self, target: &Target) -> Result<(), Error> {
         // Create a new module
         let mut module = Module::new();
+        // Same module in text format
+        let mut text = wat::ModuleText::default();
         // Type section for void function
         let mut types = TypeSection::new();
+        let mut function_types: Vec<(&[ValType], &[ValType])> = vec![];
         // Types for main function
-        types.function([], []);
+        function_types.push((&[], &[]));
         match target {
             Target::Browser => {
-                // Types for JS functions
-                todo!();
+                // Types for read and write
+                function_types.push((&[], &[ValType::I32]));
+                function_types.push((&[ValType::I32, ValType::I32], &[]));
             }
             Target::Wasi => {
                 // Types for fd_read and fd_write
-                types.function(
-                    vec![ValType::I32, ValType::I32, ValType::I32, ValType::I32],
-                    vec![ValType::I32],
-                );
-                types.function(
-                    vec![ValType::I32, ValType::I32, ValType::I32, ValType::I32],
-                    vec![ValType::I32],
-                );
+                function_types.push((&[ValType::I32; 4], &[ValType::I32]));
+                function_types.push((&[ValType::I32; 4], &[ValType::I32]));
             }
         }
+        // Types for tape error function
+        function_types.push((&[ValType::I32; 3], &[]));
+        // Types for tape growth function
+        function_types.push((&[ValType::I32; 3], &[ValType::I32]));
+        for (params, results) in function_types {
+            types.function(params.to_vec(), results.to_vec());
+            text.ty(params, results);
+        }
         module.section(&types);
         let mut imports = ImportSection::new();
-        match target {
-            Target::Browser => {
-                // Import JS functions
-                todo!();
-            }
-            Target::Wasi => {
-                // Import WASI functions
-                imports.import("wasi_unstable", "fd_read", EntityType::Function(1));
-                imports.import("wasi_unstable", "fd_write", EntityType::Function(2));
-            }
-        }
+        let (import_module, read, write) = match target {
+            // Import JS functions
+            Target::Browser => (
+                compiler::BROWSER_MODULE,
+                compiler::BROWSER_READ,
+                compiler::BROWSER_WRITE,
+            ),
+            // Import WASI functions
+            Target::Wasi => ("wasi_unstable", "fd_read", "fd_write"),
+        };
+        imports.import(import_module, read, EntityType::Function(1));
+        imports.import(import_module, write, EntityType::Function(2));
+        text.import(import_module, read, 1);
+        text.import(import_module, write, 2);
         module.section(&imports);
 
         let mut functions = FunctionSection::new();
         let type_index = 0;
         functions.function(type_index);
+        functions.function(3);
+        functions.function(4);
         module.section(&functions);
 
+        let tape_end = self.check_tape_start(compiler::TAPE_START as u64)?;
+        let minimum = (tape_end + 0xffff) >> 16;
+        let maximum = match self.tape_mode {
+            TapeMode::Growable => self.max_pages.map(u64::from),
+            TapeMode::Fixed | TapeMode::Wrap => Some(minimum),
+        };
+        if let Some(max_pages) = maximum {
+            if max_pages < minimum {
+                return Err(Error::Codegen(format!(
+                    "Maximum of {max_pages} pages is below the initial {minimum} pages"
+                )));
+            }
+        }
         let mut memories = MemorySection::new();
         // Memory for Input Stream
         memories.memory(MemoryType {
-            minimum: 3, // 30,000 elements roughly 2 pages
-            maximum: None,
+            minimum,
+            maximum,
             memory64: false,
             shared: false,
         });
 
+        text.memory(minimum, maximum);
+
         module.section(&memories);
 
         let mut exports = ExportSection::new();
         exports.export("memory", wasm_encoder::ExportKind::Memory, 0);
         exports.export("_start", wasm_encoder::ExportKind::Func, 2);
+        text.export("memory", "memory", 0);
+        text.export("_start", "func", 2);
         module.section(&exports);
 
         // let start = match target {
@@ -133,17 +250,23 @@ impl<'a> Language<'a> {
         // Local 1 : I32 I/O Buffer pointer
         // Local 2 : I32 I/O Buffer max-size
         // Local 3 : I32 I/O Vector start
+        // Local 4 : I32 Input Buffer pointer, the byte read in browsers
+        // Local 5 : I32 Input Buffer end
+        // Local 6 : I32 Tape end
+        // Local 7 : I32 Wrapped address
+        // Local 8 : I64 Cells of a wide scan
         // ...
-        let locals = vec![(4, ValType::I32)];
-        let mut f = Function::new(locals);
+        let locals = vec![(8, ValType::I32), (1, ValType::I64)];
+        let mut f = wat::Body::new(locals, self.emit_wat);
 
         // <-- Linear Memory Model -->
         // ---------------------------
-        // | I/O Buffer | I/O Vectors |  TAPE    |
-        // 0-----------516-----------1024------4096|
-        // TODO: Check if memory pointer invalid
-        // TODO: Tape expansion
-        f.instruction(&Instruction::I32Const(1024));
+        // | I/O Buffer | I/O Vectors | Input Buffer |  TAPE    |
+        // 0-----------1004----------1024-----------2048------tape end|
+        // A growable tape grows with memory as the tape header moves right
+        f.instruction(&Instruction::I32Const(
+            compiler::TAPE_START + self.tape_start as i32 * self.cell_width.bytes(),
+        ));
         f.instruction(&Instruction::LocalSet(0));
         f.instruction(&Instruction::I32Const(0));
         f.instruction(&Instruction::LocalSet(1));
@@ -151,120 +274,318 @@ impl<'a> Language<'a> {
         f.instruction(&Instruction::LocalSet(2));
         f.instruction(&Instruction::I32Const(1004));
         f.instruction(&Instruction::LocalSet(3));
-
-        // Symbol matching
-        let mut fcount: u32 = 2;
-        for symb in &self.code {
-            match symb {
-                '<' => compiler::less_than(&mut f),
-                '>' => compiler::greater_than(&mut f),
-                '+' => compiler::plus(&mut f),
-                '-' => compiler::minus(&mut f),
-                ',' => compiler::comma(&mut f, target),
-                '.' => compiler::dot(&mut f, target),
-                '[' => {
-                    fcount += 1;
-                    compiler::sq_start(&mut f, fcount);
-                }
-                ']' => compiler::sq_end(&mut f),
-                _ => {
-                    f.instruction(&Instruction::Nop);
-                }
+        f.instruction(&Instruction::I32Const(1024));
+        f.instruction(&Instruction::LocalSet(4));
+        f.instruction(&Instruction::I32Const(1024));
+        f.instruction(&Instruction::LocalSet(5));
+        match self.tape_mode {
+            TapeMode::Growable => {
+                f.instruction(&Instruction::MemorySize(0));
+                f.instruction(&Instruction::I32Const(16));
+                f.instruction(&Instruction::I32Shl);
+            }
+            TapeMode::Fixed | TapeMode::Wrap => {
+                f.instruction(&Instruction::I32Const(tape_end as i32));
             }
         }
+        f.instruction(&Instruction::LocalSet(6));
+
+        // Operation matching
+        let config = compiler::Config {
+            target: *target,
+            cell_width: self.cell_width,
+            eof_mode: self.eof_mode,
+            tape: self.tape_mode,
+            tape_length: self.tape_length,
+            checked: self.checked,
+        };
+        compiler::ops(&mut f, &self.program, &config);
 
         // Flush Stdout
         compiler::flush_stdout(&mut f, target);
         // Mark program end
         f.instruction(&Instruction::End);
 
-        codes.function(&f);
+        let tape_error = compiler::tape_error(target, self.emit_wat);
+        let grow_tape = compiler::grow_tape(self.emit_wat);
+        codes.function(f.function());
+        codes.function(tape_error.function());
+        codes.function(grow_tape.function());
+        text.func("_start", 0, &f);
+        text.func("tape_error", 3, &tape_error);
+        text.func("grow_tape", 4, &grow_tape);
         module.section(&codes);
 
         self.wasm_bytes = module.finish();
+        if self.emit_wat {
+            self.wat = text.finish();
+        }
+        Ok(())
+    }
+
+    /// Generate a static x86-64 Linux executable without going through wasm
+    ///
+    /// The tape is fixed and unchecked.
+    pub fn generate_native(&mut self) -> Result<(), Error> {
+        // Addresses of cells are 32 bit immediates
+        self.check_tape_start(native::TAPE as u64)?;
+
+        let config = native::Config {
+            cell_width: self.cell_width,
+            eof_mode: self.eof_mode,
+            tape_length: self.tape_length,
+            tape_start: self.tape_start,
+        };
+        self.native_bytes = native::executable(&self.program, &config);
         Ok(())
     }
 
+    /// Write generated native executable to file
+    pub fn write_native(&self, output_file: &str) -> Result<(), Error> {
+        fs::write(output_file, &self.native_bytes)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output_file, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    /// Generate C source with the same behavior as the WASM bytecode
+    pub fn generate_c(&mut self) -> Result<(), Error> {
+        // Same tape as the WASM memory
+        self.check_tape_start(compiler::TAPE_START as u64)?;
+        let bytes = self.cell_width.bytes() as u64;
+        let config = c::Config {
+            cell_width: self.cell_width,
+            eof_mode: self.eof_mode,
+            tape: self.tape_mode,
+            tape_length: self.tape_length,
+            tape_start: self.tape_start,
+            // Cells which fit in the memory of the WASM module
+            max_length: self.max_pages.map(|pages| {
+                ((pages as u64) << 16).saturating_sub(compiler::TAPE_START as u64) / bytes
+            }),
+            checked: self.checked,
+        };
+        let input_name = match &self.source {
+            Source::File(path) => path.file_name().unwrap_or_default().to_string_lossy(),
+            Source::Code(_) => "source code".into(),
+        };
+        self.c_source = c::source(&self.program, &config, &input_name);
+        Ok(())
+    }
+
+    /// Write generated C source to file
+    pub fn write_c(&self, output_file: &str) -> Result<(), Error> {
+        Ok(fs::write(output_file, &self.c_source)?)
+    }
+
     /// Validate generated WASM bytecode
-    pub fn validate(&self) -> Result<wasmparser::types::Types, wasmparser::BinaryReaderError> {
-        wasmparser::validate(&self.wasm_bytes)
+    pub fn validate(&self) -> Result<wasmparser::types::Types, Error> {
+        Ok(wasmparser::validate(&self.wasm_bytes)?)
     }
 
+    /// Optimize the parsed program according to the optimization level
     pub fn optimize(&mut self) {
-        // Generate tmpfir and files
-        let tmp_dir = temp_dir();
+        let program = std::mem::take(&mut self.program);
+        self.program = match self.opt_level {
+            OptLevel::O0 => program,
+            OptLevel::O1 => passes::fold(program, self.checked),
+            OptLevel::O2 | OptLevel::O3 => {
+                let config = passes::Config {
+                    checked: self.checked,
+                    tape: self.tape_mode,
+                    tape_length: self.tape_length,
+                };
+                passes::optimize(program, &config)
+            }
+        };
+    }
+
+    /// Optimize generated WASM bytecode with wasm-opt at `-O3`, downloaded on first use
+    pub fn optimize_wasm(&mut self) -> Result<(), Error> {
+        if self.opt_level < OptLevel::O3 {
+            return Ok(());
+        }
 
-        let mut unopt_file = tmp_dir.clone();
-        unopt_file.set_file_name(Uuid::new_v4().simple().to_string());
-        unopt_file.set_extension(".wasm");
+        // wasm-opt rewrites every .wasm file in a directory, so the module
+        // gets a directory of its own which is removed afterwards
+        let tmp_dir = temp_dir().join(format!("brainfk-rs-{}", Uuid::new_v4().simple()));
+        fs::create_dir(&tmp_dir)?;
+        let result = self.run_wasm_opt(&tmp_dir);
+        fs::remove_dir_all(&tmp_dir)?;
+        result
+    }
 
-        fs::write(&unopt_file, &self.wasm_bytes).unwrap();
+    /// Run wasm-opt on the module in an empty directory
+    fn run_wasm_opt(&mut self, tmp_dir: &Path) -> Result<(), Error> {
+        let module_file = tmp_dir.join("module.wasm");
+        fs::write(&module_file, &self.wasm_bytes)?;
 
         // Set wasm-bindgen STDOUt logging to quiet
         PBAR.set_quiet(true);
 
         // Download and run wasm-opt
-        wasm_opt::run(
-            &cache::get_wasm_pack_cache().unwrap(),
-            tmp_dir.as_path(),
-            &["--flatten --precompute --optimize-instructions --local-cse".to_string()],
-            //&["-O4".to_string()],
-            true,
-        )
-        .unwrap();
+        let cache = cache::get_wasm_pack_cache().map_err(|e| Error::Optimization(e.to_string()))?;
+        let args = [
+            "--flatten",
+            "--precompute",
+            "--optimize-instructions",
+            "--local-cse",
+        ]
+        .map(String::from);
+        wasm_opt::run(&cache, tmp_dir, &args, true)
+            .map_err(|e| Error::Optimization(e.to_string()))?;
 
         // Update wasm-bytes
-        self.wasm_bytes = fs::read(&unopt_file).unwrap();
+        self.wasm_bytes = fs::read(&module_file)?;
+        Ok(())
     }
 
     /// Write generated WASM bytecode to file
-    pub fn write_wasm(&self, output_file: &str) -> Result<(), io::Error> {
-        fs::write(output_file, &self.wasm_bytes)
+    pub fn write_wasm(&self, output_file: &str) -> Result<(), Error> {
+        Ok(fs::write(output_file, &self.wasm_bytes)?)
+    }
+
+    /// Write the module in text format generated with `emit_wat` to file
+    pub fn write_wat(&self, output_file: &str) -> Result<(), Error> {
+        Ok(fs::write(output_file, &self.wat)?)
+    }
+
+    /// Write an ES module and its TypeScript declarations loading the browser
+    /// targeted wasm file next to it
+    pub fn write_glue(&self, output_file: &str) -> Result<(), Error> {
+        let wasm_path = Path::new(output_file);
+        let wasm_name = wasm_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid output file"))?;
+        let js_path = wasm_path.with_extension("js");
+        let js_name = js_path.file_name().unwrap().to_str().unwrap();
+        fs::write(&js_path, glue::javascript(wasm_name))?;
+        fs::write(wasm_path.with_extension("d.ts"), glue::typescript(js_name))?;
+        Ok(())
     }
 
     /// Generate wasmu
-    pub fn compile_wasmu(&mut self, backend: &Backend) -> Result<(), CompileError> {
+    pub fn compile_wasmu(&mut self, backend: &Backend) -> Result<(), Error> {
         let engine = match backend {
             &Backend::LLVM => Universal::new(LLVM::default()).engine(),
             &Backend::Cranelift => Universal::new(Cranelift::default()).engine(),
             &Backend::Singlepass => Universal::new(Singlepass::default()).engine(),
         };
         let store = Store::new(&engine);
-        self.module = Some(WasmerModule::new(&store, &self.wasm_bytes)?);
+        let module = WasmerModule::new(&store, &self.wasm_bytes)
+            .map_err(|e| Error::Compile(e.to_string()))?;
+        self.module = Some(module);
         Ok(())
     }
 
     /// Write generated WASM bytecode to file
-    pub fn write_wasmu(&self, output_file: &str) -> Result<(), wasmer::SerializeError> {
-        self.module.as_ref().unwrap().serialize_to_file(output_file)
+    pub fn write_wasmu(&self, output_file: &str) -> Result<(), Error> {
+        self.compiled_module()?
+            .serialize_to_file(output_file)
+            .map_err(|e| Error::Compile(e.to_string()))
+    }
+
+    /// Module compiled by `compile_wasmu`
+    fn compiled_module(&self) -> Result<&WasmerModule, Error> {
+        self.module
+            .as_ref()
+            .ok_or_else(|| Error::Compile("Module has not been compiled".to_string()))
     }
 
-    /// TODO: Compile to a binary using wasmer
-    pub fn compile_binary(&self, _output_file: &str, _backend: &Backend) -> Result<(), io::Error> {
-        todo!();
+    /// Compile to a standalone binary
+    ///
+    /// The binary is a copy of the `runner` executable followed by the
+    /// serialized module, its length and `EMBEDDED_MAGIC`. The runner has to
+    /// be brainfk-runner built for this host with the same wasmer, as the
+    /// module is compiled for this host only.
+    pub fn compile_binary(
+        &mut self,
+        output_file: &str,
+        runner: &Path,
+        backend: &Backend,
+    ) -> Result<(), Error> {
+        self.compile_wasmu(backend)?;
+        let wasmu = self
+            .compiled_module()?
+            .serialize()
+            .map_err(|e| Error::Compile(e.to_string()))?;
+
+        let mut binary = fs::read(runner)
+            .map_err(|e| io::Error::new(e.kind(), format!("Runner {}: {e}", runner.display())))?;
+        binary.extend_from_slice(&wasmu);
+        binary.extend_from_slice(&(wasmu.len() as u64).to_le_bytes());
+        binary.extend_from_slice(EMBEDDED_MAGIC);
+        fs::write(output_file, binary)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output_file, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
     }
 
     /// Run bf-code
-    pub fn run(&self) {
-        let module = self.module.as_ref().unwrap();
-
-        // Suppresses IO during benching
-        let mut wasi_env = if self.suppress_io {
-            WasiState::new("brainfk")
-                .stdin(Box::new(Pipe::new()))
-                .stdout(Box::new(Pipe::new()))
-                .finalize()
-                .unwrap()
-        } else {
-            WasiState::new("brainfk")
-                .stdin(Box::new(Stdin))
-                .stdout(Box::new(Stdout))
-                .finalize()
-                .unwrap()
-        };
-        let import_object = wasi_env.import_object(&module).unwrap();
-        let instance = Instance::new(&module, &import_object).unwrap();
-        let start = instance.exports.get_function("_start").unwrap();
-        start.call(&[]).unwrap();
+    pub fn run(&self) -> Result<(), Error> {
+        run_module(self.compiled_module()?, self.suppress_io)
+    }
+}
+
+/// Run a compiled module with WASI
+fn run_module(module: &WasmerModule, suppress_io: bool) -> Result<(), Error> {
+    // Suppresses IO during benching
+    let mut wasi_env = if suppress_io {
+        WasiState::new("brainfk")
+            .stdin(Box::new(Pipe::new()))
+            .stdout(Box::new(Pipe::new()))
+            .finalize()
+    } else {
+        WasiState::new("brainfk")
+            .stdin(Box::new(Stdin))
+            .stdout(Box::new(Stdout))
+            .finalize()
+    }
+    .map_err(|e| Error::Instantiation(e.to_string()))?;
+    let import_object = wasi_env
+        .import_object(module)
+        .map_err(|e| Error::Instantiation(e.to_string()))?;
+    let instance =
+        Instance::new(module, &import_object).map_err(|e| Error::Instantiation(e.to_string()))?;
+    let start = instance
+        .exports
+        .get_function("_start")
+        .map_err(|e| Error::Instantiation(e.to_string()))?;
+    start.call(&[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_memory_source() -> Result<(), Error> {
+        // Code received at runtime compiles without a file
+        let mut brainfk = Language::from_source("++++++++[>++++++++<-]>+.");
+        brainfk.parse()?;
+        brainfk.optimize();
+        brainfk.generate_wasm(&Target::Wasi)?;
+        brainfk.validate()?;
+
+        let mut brainfk = Language::from_reader("+[-],.".as_bytes())?;
+        brainfk.parse()?;
+        brainfk.generate_c()?;
+        assert!(brainfk.c_source.contains("from source code"));
+
+        // Diagnostics still point into the code
+        let mut brainfk = Language::from_source("+]");
+        match brainfk.parse() {
+            Err(Error::Parse { line, column, .. }) => assert_eq!((line, column), (1, 2)),
+            other => panic!("expected a parse error, found {other:?}"),
+        }
+        Ok(())
     }
 }
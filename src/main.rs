@@ -1,7 +1,10 @@
 use brainfk_rs::cmd::{Cli, Run};
 use clap::Parser;
-use std::io;
+use std::process;
 
-fn main() -> Result<(), io::Error> {
-    Cli::parse().run()
+fn main() {
+    if let Err(e) = Cli::parse().run() {
+        eprintln!("Error: {e}");
+        process::exit(e.exit_code());
+    }
 }
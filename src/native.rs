@@ -0,0 +1,368 @@
+use crate::cmd::{CellWidth, EofMode};
+use crate::ir::{Instr, Op};
+
+/// Address the executable is loaded at
+const BASE: u32 = 0x40_0000;
+/// Size of the ELF header and the two program headers in front of the code
+const HEADERS: u32 = 64 + 2 * 56;
+/// Address of the zero initialized data
+const DATA: u32 = 0x1000_0000;
+/// Size of the output buffer at the start of the data
+const OUT_SIZE: u32 = 4096;
+/// Address of the byte read by `,`
+const IN_BYTE: u32 = DATA + OUT_SIZE;
+/// Address of the first cell of the tape
+pub(crate) const TAPE: u32 = DATA + 2 * OUT_SIZE;
+
+/// Settings of the executable, whose tape is always fixed and unchecked
+pub(crate) struct Config {
+    pub(crate) cell_width: CellWidth,
+    pub(crate) eof_mode: EofMode,
+    pub(crate) tape_length: u32,
+    pub(crate) tape_start: u32,
+}
+
+/// Machine code under construction
+///
+/// Register usage
+/// rbx : Tape header
+/// r13 : Output buffer length
+/// rax : Scratch
+struct Asm {
+    code: Vec<u8>,
+    /// Positions of the operands of calls to the flush helper
+    flush_calls: Vec<usize>,
+    /// Positions of the operands of calls to the read helper
+    read_calls: Vec<usize>,
+}
+
+/// Generate a static x86-64 Linux executable running the program
+pub(crate) fn executable(program: &[Instr], config: &Config) -> Vec<u8> {
+    let cell = &config.cell_width;
+    let mut asm = Asm {
+        code: Vec::new(),
+        flush_calls: Vec::new(),
+        read_calls: Vec::new(),
+    };
+
+    // mov ebx, tape start ; xor r13d, r13d
+    asm.code.push(0xBB);
+    asm.imm32((TAPE + config.tape_start * cell.bytes() as u32) as i32);
+    asm.code.extend([0x45, 0x31, 0xED]);
+
+    ops(&mut asm, program, config);
+
+    // Flush Stdout and exit with 0
+    asm.call_flush();
+    asm.code.extend([0xB8, 0x3C, 0x00, 0x00, 0x00]); // mov eax, 60 (exit)
+    asm.code.extend([0x31, 0xFF]); // xor edi, edi
+    asm.code.extend([0x0F, 0x05]); // syscall
+
+    asm.helpers();
+
+    let data_size = 2 * OUT_SIZE as u64 + config.tape_length as u64 * cell.bytes() as u64;
+    elf(&asm.code, data_size)
+}
+
+/// Emit code for a sequence of operations
+///
+/// Moves only change the displacement of the `[rbx + disp32]` accesses after
+/// them, as `compiler::ops` does with offsets. rbx catches up before scans,
+/// I/O, loops and at the end of the sequence.
+fn ops(asm: &mut Asm, ops: &[Instr], config: &Config) {
+    let cell = &config.cell_width;
+    // Cells moved since rbx last caught up
+    let mut offset = 0;
+    for Instr { op, .. } in ops {
+        if matches!(op, Op::Scan(_) | Op::Input | Op::Output | Op::Loop(_)) {
+            asm.shift(offset, cell);
+            offset = 0;
+        }
+        match op {
+            Op::Add(n) => asm.add(offset, *n, cell),
+            Op::Move(n) => offset += n,
+            Op::Clear => asm.clear(offset, cell),
+            Op::MulAdd { offset: to, factor } => asm.mul_add(offset, offset + to, *factor, cell),
+            Op::Scan(n) => asm.scan(*n, cell),
+            Op::Input => asm.input(cell, &config.eof_mode),
+            Op::Output => asm.output(cell),
+            Op::Loop(body) => {
+                // Skip the loop if the cell is zero
+                asm.cmp_zero(0, cell);
+                asm.code.extend([0x0F, 0x84]); // je
+                let skip = asm.code.len();
+                asm.imm32(0);
+                let top = asm.code.len();
+                self::ops(asm, body, config);
+                // Repeat while the cell is non-zero
+                asm.cmp_zero(0, cell);
+                asm.code.extend([0x0F, 0x85]); // jne
+                asm.imm32(top as i32 - (asm.code.len() as i32 + 4));
+                asm.patch32(skip, asm.code.len());
+            }
+        }
+    }
+    asm.shift(offset, cell);
+}
+
+impl Asm {
+    fn imm32(&mut self, value: i32) {
+        self.code.extend(value.to_le_bytes());
+    }
+
+    /// Immediate of the size of a cell, 64 bit cells take sign extended 32 bits
+    fn imm_cell(&mut self, value: i32, cell: &CellWidth) {
+        match cell {
+            CellWidth::Bits8 => self.code.push(value as u8),
+            CellWidth::Bits16 => self.code.extend((value as u16).to_le_bytes()),
+            CellWidth::Bits32 | CellWidth::Bits64 => self.imm32(value),
+        }
+    }
+
+    /// Point the rel32 operand at `pos` to `target`
+    fn patch32(&mut self, pos: usize, target: usize) {
+        let rel = target as i32 - (pos as i32 + 4);
+        self.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// Emit the opcode of an instruction on a cell, `op8` for byte cells and
+    /// `op` with operand size prefixes otherwise, `rex` adds REX bits
+    fn cell_op(&mut self, cell: &CellWidth, op8: u8, op: u8, rex: u8) {
+        match cell {
+            CellWidth::Bits8 => {
+                if rex != 0 {
+                    self.code.push(rex);
+                }
+                self.code.push(op8);
+            }
+            CellWidth::Bits16 => {
+                self.code.push(0x66);
+                if rex != 0 {
+                    self.code.push(rex);
+                }
+                self.code.push(op);
+            }
+            CellWidth::Bits32 => {
+                if rex != 0 {
+                    self.code.push(rex);
+                }
+                self.code.push(op);
+            }
+            CellWidth::Bits64 => {
+                self.code.push(rex | 0x48);
+                self.code.push(op);
+            }
+        }
+    }
+
+    /// Move tape header by n cells
+    fn shift(&mut self, n: i32, cell: &CellWidth) {
+        if n != 0 {
+            // lea rbx, [rbx + n * bytes]
+            self.code.extend([0x48, 0x8D, 0x9B]);
+            self.imm32(n * cell.bytes());
+        }
+    }
+
+    /// Add n to the cell `offset` cells from the tape header
+    fn add(&mut self, offset: i32, n: i32, cell: &CellWidth) {
+        // add [rbx + disp32], imm
+        self.cell_op(cell, 0x80, 0x81, 0);
+        self.code.push(0x83);
+        self.imm32(offset * cell.bytes());
+        self.imm_cell(n, cell);
+    }
+
+    /// Set the cell `offset` cells from the tape header to 0
+    fn clear(&mut self, offset: i32, cell: &CellWidth) {
+        // mov [rbx + disp32], 0
+        self.cell_op(cell, 0xC6, 0xC7, 0);
+        self.code.push(0x83);
+        self.imm32(offset * cell.bytes());
+        self.imm_cell(0, cell);
+    }
+
+    /// Compare the cell `offset` cells from the tape header to 0
+    fn cmp_zero(&mut self, offset: i32, cell: &CellWidth) {
+        // cmp [rbx + disp32], 0
+        self.cell_op(cell, 0x80, 0x83, 0);
+        self.code.push(0xBB);
+        self.imm32(offset * cell.bytes());
+        self.code.push(0x00);
+    }
+
+    /// Add the cell `from` times factor to the cell `to`, cells away from the
+    /// tape header
+    fn mul_add(&mut self, from: i32, to: i32, factor: i32, cell: &CellWidth) {
+        // A zero counter means the loop body never ran
+        self.cmp_zero(from, cell);
+        self.code.extend([0x74, 0x00]); // je
+        let skip = self.code.len();
+
+        // Load cell zero extended into rax
+        match cell {
+            CellWidth::Bits8 => self.code.extend([0x0F, 0xB6, 0x83]),
+            CellWidth::Bits16 => self.code.extend([0x0F, 0xB7, 0x83]),
+            CellWidth::Bits32 => self.code.extend([0x8B, 0x83]),
+            CellWidth::Bits64 => self.code.extend([0x48, 0x8B, 0x83]),
+        }
+        self.imm32(from * cell.bytes());
+        if factor != 1 {
+            // imul rax, rax, factor
+            if let CellWidth::Bits64 = cell {
+                self.code.push(0x48);
+            }
+            self.code.extend([0x69, 0xC0]);
+            self.imm32(factor);
+        }
+        // add [rbx + disp32], rax
+        self.cell_op(cell, 0x00, 0x01, 0);
+        self.code.push(0x83);
+        self.imm32(to * cell.bytes());
+
+        self.code[skip - 1] = (self.code.len() - skip) as u8;
+    }
+
+    /// Move tape header by n cells until the cell at the tape header is 0
+    fn scan(&mut self, n: i32, cell: &CellWidth) {
+        let top = self.code.len();
+        self.cmp_zero(0, cell);
+        self.code.extend([0x74, 0x09]); // je over lea and jmp
+        self.shift(n, cell);
+        self.code.push(0xEB); // jmp
+        self.code
+            .push((top as i32 - (self.code.len() as i32 + 1)) as u8);
+    }
+
+    /// Append the cell at the tape header to the output buffer
+    fn output(&mut self, cell: &CellWidth) {
+        // mov rax, [rbx]
+        self.cell_op(cell, 0x8A, 0x8B, 0);
+        self.code.push(0x03);
+        // mov [r13 + DATA], rax
+        self.cell_op(cell, 0x88, 0x89, 0x41);
+        self.code.push(0x85);
+        self.imm32(DATA as i32);
+        // add r13, bytes
+        self.code.extend([0x49, 0x83, 0xC5, cell.bytes() as u8]);
+        // Flush once another cell might not fit
+        // cmp r13, OUT_SIZE - 8 ; jb over call
+        self.code.extend([0x49, 0x81, 0xFD]);
+        self.imm32(OUT_SIZE as i32 - 8);
+        self.code.extend([0x72, 0x05]);
+        self.call_flush();
+    }
+
+    /// Read a byte of input into the cell at the tape header
+    fn input(&mut self, cell: &CellWidth, eof: &EofMode) {
+        // Byte read or -1 at end of input in rax
+        self.code.push(0xE8);
+        self.read_calls.push(self.code.len());
+        self.imm32(0);
+
+        match eof {
+            EofMode::Zero => {
+                // test rax, rax ; jns over xor ; xor eax, eax
+                self.code.extend([0x48, 0x85, 0xC0, 0x79, 0x02, 0x31, 0xC0]);
+            }
+            EofMode::MinusOne => {}
+            EofMode::Unchanged => {
+                // test rax, rax ; js over mov
+                self.code.extend([0x48, 0x85, 0xC0, 0x78, 0x00]);
+            }
+        }
+        let skip = self.code.len();
+        // mov [rbx], rax
+        self.cell_op(cell, 0x88, 0x89, 0);
+        self.code.push(0x03);
+        if let EofMode::Unchanged = eof {
+            self.code[skip - 1] = (self.code.len() - skip) as u8;
+        }
+    }
+
+    fn call_flush(&mut self) {
+        self.code.push(0xE8);
+        self.flush_calls.push(self.code.len());
+        self.imm32(0);
+    }
+
+    /// Emit the flush and read helpers and point their calls at them
+    fn helpers(&mut self) {
+        // Write the output buffer to Stdout and empty it
+        let flush = self.code.len();
+        self.code.extend([0xB8, 0x01, 0x00, 0x00, 0x00]); // mov eax, 1 (write)
+        self.code.extend([0xBF, 0x01, 0x00, 0x00, 0x00]); // mov edi, 1 (Stdout)
+        self.code.push(0xBE); // mov esi, DATA
+        self.imm32(DATA as i32);
+        self.code.extend([0x4C, 0x89, 0xEA]); // mov rdx, r13
+        self.code.extend([0x0F, 0x05]); // syscall
+        self.code.extend([0x45, 0x31, 0xED]); // xor r13d, r13d
+        self.code.push(0xC3); // ret
+
+        // Read a byte from Stdin into rax, -1 at end of input
+        let read = self.code.len();
+        self.code.extend([0x31, 0xC0]); // xor eax, eax (read)
+        self.code.extend([0x31, 0xFF]); // xor edi, edi (Stdin)
+        self.code.push(0xBE); // mov esi, IN_BYTE
+        self.imm32(IN_BYTE as i32);
+        self.code.extend([0xBA, 0x01, 0x00, 0x00, 0x00]); // mov edx, 1
+        self.code.extend([0x0F, 0x05]); // syscall
+        self.code.extend([0x48, 0x83, 0xF8, 0x01]); // cmp rax, 1
+        self.code.extend([0x75, 0x09]); // jne over movzx and ret
+        self.code.extend([0x0F, 0xB6, 0x04, 0x25]); // movzx eax, byte [IN_BYTE]
+        self.imm32(IN_BYTE as i32);
+        self.code.push(0xC3); // ret
+        self.code.extend([0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]); // mov rax, -1
+        self.code.push(0xC3); // ret
+
+        for pos in std::mem::take(&mut self.flush_calls) {
+            self.patch32(pos, flush);
+        }
+        for pos in std::mem::take(&mut self.read_calls) {
+            self.patch32(pos, read);
+        }
+    }
+}
+
+/// Wrap code in an ELF executable loading it at `BASE` with `data_size`
+/// zeroed bytes at `DATA`
+fn elf(code: &[u8], data_size: u64) -> Vec<u8> {
+    let file_size = (HEADERS as usize + code.len()) as u64;
+    let mut elf = Vec::with_capacity(file_size as usize);
+
+    // ELF header
+    elf.extend(b"\x7fELF");
+    elf.extend([2, 1, 1, 0]); // 64 bit, little endian, version 1, System V
+    elf.extend([0; 8]);
+    elf.extend(2u16.to_le_bytes()); // Executable
+    elf.extend(0x3Eu16.to_le_bytes()); // x86-64
+    elf.extend(1u32.to_le_bytes()); // Version
+    elf.extend((BASE as u64 + HEADERS as u64).to_le_bytes()); // Entry point
+    elf.extend(64u64.to_le_bytes()); // Program headers offset
+    elf.extend(0u64.to_le_bytes()); // No section headers
+    elf.extend(0u32.to_le_bytes()); // Flags
+    elf.extend(64u16.to_le_bytes()); // ELF header size
+    elf.extend(56u16.to_le_bytes()); // Program header size
+    elf.extend(2u16.to_le_bytes()); // Program header count
+    elf.extend([0; 6]); // No section headers
+
+    // Code along with the headers
+    program_header(&mut elf, 5, BASE, file_size, file_size);
+    // Zero initialized data
+    program_header(&mut elf, 6, DATA, 0, data_size);
+
+    elf.extend(code);
+    elf
+}
+
+/// Loadable segment at `vaddr` with `flags` from the start of the file
+fn program_header(elf: &mut Vec<u8>, flags: u32, vaddr: u32, file_size: u64, mem_size: u64) {
+    elf.extend(1u32.to_le_bytes()); // Loadable
+    elf.extend(flags.to_le_bytes());
+    elf.extend(0u64.to_le_bytes()); // File offset
+    elf.extend((vaddr as u64).to_le_bytes());
+    elf.extend((vaddr as u64).to_le_bytes());
+    elf.extend(file_size.to_le_bytes());
+    elf.extend(mem_size.to_le_bytes());
+    elf.extend(0x1000u64.to_le_bytes()); // Page alignment
+}
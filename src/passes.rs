@@ -0,0 +1,326 @@
+use crate::cmd::TapeMode;
+use crate::ir::{Instr, Op};
+use std::collections::BTreeMap;
+
+/// Settings of the generated code which passes have to preserve
+pub struct Config {
+    /// Keep moves apart, see `fold`
+    pub checked: bool,
+    pub tape: TapeMode,
+    pub tape_length: u32,
+}
+
+/// Run every optimization pass over a program
+pub fn optimize(ops: Vec<Instr>, config: &Config) -> Vec<Instr> {
+    let ops = fold(ops, config.checked);
+    let ops = mul_loops(clear_loops(ops), config);
+    let ops = scan_loops(ops);
+    // Removing dead code can bring runs next to each other
+    fold(dead_loops(ops), config.checked)
+}
+
+/// Fold runs of `+`/`-` and `<`/`>` into a single operation
+///
+/// When `checked`, moves are kept apart so each one is checked for moving off
+/// the tape and reported as its own instruction.
+pub fn fold(ops: Vec<Instr>, checked: bool) -> Vec<Instr> {
+    let mut folded: Vec<Instr> = Vec::with_capacity(ops.len());
+    for Instr {
+        op,
+        index,
+        position,
+    } in ops
+    {
+        match (folded.last_mut().map(|last| &mut last.op), op) {
+            (Some(Op::Add(a)), Op::Add(b)) => *a = a.wrapping_add(b),
+            (Some(Op::Move(a)), Op::Move(b)) if !checked => *a += b,
+            (_, Op::Loop(body)) => folded.push(Instr {
+                op: Op::Loop(fold(body, checked)),
+                index,
+                position,
+            }),
+            (_, op) => folded.push(Instr {
+                op,
+                index,
+                position,
+            }),
+        }
+        // Drop runs which cancel out e.g. `+-` or `><`
+        if let Some(Op::Add(0) | Op::Move(0)) = folded.last().map(|last| &last.op) {
+            folded.pop();
+        }
+    }
+    folded
+}
+
+/// Replace clear loops like `[-]` and `[+]` with a direct store of zero
+pub fn clear_loops(ops: Vec<Instr>) -> Vec<Instr> {
+    ops.into_iter()
+        .map(|Instr { op, index, position }| match op {
+            // An odd step always reaches zero whatever the cell width
+            Op::Loop(body) if matches!(body[..], [Instr { op: Op::Add(n), .. }] if n % 2 != 0) => {
+                Instr {
+                    op: Op::Clear,
+                    index,
+                    position,
+                }
+            }
+            Op::Loop(body) => Instr {
+                op: Op::Loop(clear_loops(body)),
+                index,
+                position,
+            },
+            op => Instr { op, index, position },
+        })
+        .collect()
+}
+
+/// Replace scan loops like `[>]` and `[<<]` with a dedicated search
+pub fn scan_loops(ops: Vec<Instr>) -> Vec<Instr> {
+    ops.into_iter()
+        .map(
+            |Instr {
+                 op,
+                 index,
+                 position,
+             }| match op {
+                Op::Loop(body) => match body[..] {
+                    [Instr {
+                        op: Op::Move(n), ..
+                    }] => Instr {
+                        op: Op::Scan(n),
+                        index,
+                        position,
+                    },
+                    _ => Instr {
+                        op: Op::Loop(scan_loops(body)),
+                        index,
+                        position,
+                    },
+                },
+                op => Instr {
+                    op,
+                    index,
+                    position,
+                },
+            },
+        )
+        .collect()
+}
+
+/// Replace balanced loops like `[->+>++<<]` with multiply-adds followed by a clear
+///
+/// Loops adding to a cell a multiple of the length of a wrapping tape away
+/// are kept, as that cell is the loop counter itself.
+pub fn mul_loops(ops: Vec<Instr>, config: &Config) -> Vec<Instr> {
+    let mut out = Vec::with_capacity(ops.len());
+    for Instr {
+        op,
+        index,
+        position,
+    } in ops
+    {
+        match op {
+            Op::Loop(body) => match mul_factors(&body)
+                .filter(|factors| !factors.keys().any(|&offset| on_counter(offset, config)))
+            {
+                Some(factors) => {
+                    out.extend(factors.into_iter().map(|(offset, factor)| Instr {
+                        op: Op::MulAdd { offset, factor },
+                        index,
+                        position,
+                    }));
+                    out.push(Instr {
+                        op: Op::Clear,
+                        index,
+                        position,
+                    });
+                }
+                None => out.push(Instr {
+                    op: Op::Loop(mul_loops(body, config)),
+                    index,
+                    position,
+                }),
+            },
+            op => out.push(Instr {
+                op,
+                index,
+                position,
+            }),
+        }
+    }
+    out
+}
+
+/// Whether the cell `offset` cells away is the cell the tape header is on
+fn on_counter(offset: i32, config: &Config) -> bool {
+    config.tape == TapeMode::Wrap
+        && config.tape_length != 0
+        && i64::from(offset).rem_euclid(i64::from(config.tape_length)) == 0
+}
+
+/// Factors by offset of a loop body which only adds and moves, returns to
+/// its starting cell and steps that cell by one
+fn mul_factors(body: &[Instr]) -> Option<BTreeMap<i32, i32>> {
+    let mut pos = 0;
+    let mut deltas: BTreeMap<i32, i32> = BTreeMap::new();
+    for instr in body {
+        match instr.op {
+            Op::Add(n) => {
+                let delta = deltas.entry(pos).or_insert(0);
+                *delta = delta.wrapping_add(n);
+            }
+            Op::Move(n) => pos += n,
+            _ => return None,
+        }
+    }
+    // Counting up runs the loop `-cell` times instead of `cell` times
+    let sign = match deltas.remove(&0) {
+        Some(-1) => 1,
+        Some(1) => -1,
+        _ => return None,
+    };
+    if pos != 0 {
+        return None;
+    }
+    deltas.retain(|_, factor| *factor != 0);
+    deltas
+        .values_mut()
+        .for_each(|factor| *factor = factor.wrapping_mul(sign));
+    Some(deltas)
+}
+
+/// Remove loops which can never run as the cell at the tape header is zero,
+/// like loops at the start of the program or right after another loop
+pub fn dead_loops(ops: Vec<Instr>) -> Vec<Instr> {
+    // The tape starts zeroed
+    remove_dead(ops, true)
+}
+
+fn remove_dead(ops: Vec<Instr>, mut zero: bool) -> Vec<Instr> {
+    let mut out = Vec::with_capacity(ops.len());
+    for Instr {
+        op,
+        index,
+        position,
+    } in ops
+    {
+        zero = match op {
+            Op::Loop(_) | Op::Scan(_) | Op::Clear | Op::MulAdd { .. } if zero => continue,
+            Op::Loop(_) | Op::Scan(_) | Op::Clear => true,
+            Op::MulAdd { .. } | Op::Output => zero,
+            Op::Add(_) | Op::Move(_) | Op::Input => false,
+        };
+        let op = match op {
+            // The body is only entered on a non-zero cell
+            Op::Loop(body) => Op::Loop(remove_dead(body, false)),
+            op => op,
+        };
+        out.push(Instr {
+            op,
+            index,
+            position,
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse;
+
+    /// Operations of a program without positions, loops in brackets
+    fn shape(ops: &[Instr]) -> String {
+        ops.iter()
+            .map(|instr| match &instr.op {
+                Op::Loop(body) => format!("[{}]", shape(body)),
+                op => format!("{op:?}"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn fold_unchecked(ops: Vec<Instr>) -> Vec<Instr> {
+        fold(ops, false)
+    }
+
+    fn loop_body(code: &str) -> Vec<Instr> {
+        match parse(code).unwrap().remove(0).op {
+            Op::Loop(body) => body,
+            op => panic!("expected a loop, found {op:?}"),
+        }
+    }
+
+    #[test]
+    fn fold_cancels_runs() {
+        assert_eq!(
+            shape(&fold_unchecked(parse("++-[+-><]>><").unwrap())),
+            "Add(1) [] Move(1)"
+        );
+        // Runs on either side of a loop are not merged
+        assert_eq!(
+            shape(&fold_unchecked(parse("+[-]->[<]<").unwrap())),
+            "Add(1) [Add(-1)] Add(-1) Move(1) [Move(-1)] Move(-1)"
+        );
+    }
+
+    #[test]
+    fn fold_keeps_moves_when_checked() {
+        let ops = fold(parse(">>++<><<").unwrap(), true);
+        assert_eq!(
+            shape(&ops),
+            "Move(1) Move(1) Add(2) Move(-1) Move(1) Move(-1) Move(-1)"
+        );
+        let indices: Vec<usize> = ops.iter().map(|instr| instr.index).collect();
+        assert_eq!(indices, [0, 1, 2, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn clear_loops_need_odd_steps() {
+        let ops = fold_unchecked(parse("[+++][++]").unwrap());
+        assert_eq!(shape(&clear_loops(ops)), "Clear [Add(2)]");
+    }
+
+    #[test]
+    fn mul_factors_of_balanced_loops() {
+        // Counting up flips the sign of the factors
+        let factors = mul_factors(&loop_body("[+>-<]")).unwrap();
+        assert_eq!(factors.into_iter().collect::<Vec<_>>(), [(1, 1)]);
+        let factors = mul_factors(&loop_body("[->+++>>-<<<]")).unwrap();
+        assert_eq!(factors.into_iter().collect::<Vec<_>>(), [(1, 3), (3, -1)]);
+        // Not returning to the starting cell
+        assert_eq!(mul_factors(&loop_body("[->+]")), None);
+        // Nested loops
+        assert_eq!(mul_factors(&loop_body("[->[-]<]")), None);
+    }
+
+    #[test]
+    fn mul_loops_onto_the_counter() {
+        let config = |tape| Config {
+            checked: false,
+            tape,
+            tape_length: 3,
+        };
+        let ops = || fold_unchecked(parse("+[->>>+<<<]").unwrap());
+        assert_eq!(
+            shape(&mul_loops(ops(), &config(TapeMode::Fixed))),
+            "Add(1) MulAdd { offset: 3, factor: 1 } Clear"
+        );
+        // Three cells to the right of a three cell wrapping tape is the counter
+        assert_eq!(
+            shape(&mul_loops(ops(), &config(TapeMode::Wrap))),
+            "Add(1) [Add(-1) Move(3) Add(1) Move(-3)]"
+        );
+    }
+
+    #[test]
+    fn dead_loops_on_zero_cells() {
+        // At the start of the program and right after another loop
+        let ops = parse("[-]+[>][<][.]").unwrap();
+        assert_eq!(shape(&dead_loops(ops)), "Add(1) [Move(1)]");
+        // A loop body is entered with a non-zero cell
+        let ops = parse("+[[-]>]").unwrap();
+        assert_eq!(shape(&dead_loops(ops)), "Add(1) [[Add(-1)] Move(1)]");
+    }
+}
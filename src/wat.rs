@@ -0,0 +1,247 @@
+use std::fmt::{self, Write};
+use wasm_encoder::{BlockType, Function, Instruction, MemArg, ValType};
+
+/// Function body encoded along with its WebAssembly text format
+pub(crate) struct Body {
+    function: Function,
+    /// Instructions one per line, indented by block depth
+    text: String,
+    /// Whether the text is built, it is left empty otherwise
+    with_text: bool,
+    depth: usize,
+}
+
+impl Body {
+    pub(crate) fn new(locals: Vec<(u32, ValType)>, with_text: bool) -> Self {
+        let mut text = String::new();
+        if with_text && !locals.is_empty() {
+            text.push_str("    (local");
+            for (count, ty) in &locals {
+                for _ in 0..*count {
+                    write!(text, " {}", val_type(ty)).unwrap();
+                }
+            }
+            text.push_str(")\n");
+        }
+        Self {
+            function: Function::new(locals),
+            text,
+            with_text,
+            depth: 0,
+        }
+    }
+
+    /// Encode an instruction and add its text
+    pub(crate) fn instruction(&mut self, instruction: &Instruction) -> &mut Self {
+        self.function.instruction(instruction);
+        // The `end` of the function itself closes it in the module text
+        if let (Instruction::End, 0) = (instruction, self.depth) {
+            return self;
+        }
+        if let Instruction::Else | Instruction::End = instruction {
+            self.depth -= 1;
+        }
+        if self.with_text {
+            self.line(&text(instruction));
+        }
+        if let Instruction::Block(_)
+        | Instruction::Loop(_)
+        | Instruction::If(_)
+        | Instruction::Else = instruction
+        {
+            self.depth += 1;
+        }
+        self
+    }
+
+    /// Add a comment to the text at the current position
+    pub(crate) fn comment(&mut self, comment: fmt::Arguments) {
+        if self.with_text {
+            self.line(&format!(";; {comment}"));
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        // Two levels for the module and the function
+        let indent = "  ".repeat(self.depth + 2);
+        writeln!(self.text, "{indent}{line}").unwrap();
+    }
+
+    /// Encoded function
+    pub(crate) fn function(&self) -> &Function {
+        &self.function
+    }
+
+    /// Text of the locals and instructions
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Text of a value type
+pub(crate) fn val_type(ty: &ValType) -> &'static str {
+    match ty {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        // Reference types are never emitted
+        _ => unreachable!("reference type"),
+    }
+}
+
+/// Text of a block type
+fn block_type(ty: &BlockType) -> String {
+    match ty {
+        BlockType::Empty => String::new(),
+        BlockType::Result(ty) => format!(" (result {})", val_type(ty)),
+        BlockType::FunctionType(index) => format!(" (type {index})"),
+    }
+}
+
+/// Text of a memory argument for an access of `natural` alignment
+fn mem_arg(mem_arg: &MemArg, natural: u32) -> String {
+    let mut text = String::new();
+    if mem_arg.offset != 0 {
+        write!(text, " offset={}", mem_arg.offset).unwrap();
+    }
+    if mem_arg.align != natural {
+        write!(text, " align={}", 1u64 << mem_arg.align).unwrap();
+    }
+    text
+}
+
+/// Text of an instruction the compiler emits
+fn text(instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Block(ty) => format!("block{}", block_type(ty)),
+        Instruction::Loop(ty) => format!("loop{}", block_type(ty)),
+        Instruction::If(ty) => format!("if{}", block_type(ty)),
+        Instruction::Else => "else".to_string(),
+        Instruction::End => "end".to_string(),
+        Instruction::Br(depth) => format!("br {depth}"),
+        Instruction::BrIf(depth) => format!("br_if {depth}"),
+        Instruction::Call(index) => format!("call {index}"),
+        Instruction::Drop => "drop".to_string(),
+        Instruction::Select => "select".to_string(),
+        Instruction::Unreachable => "unreachable".to_string(),
+        Instruction::LocalGet(index) => format!("local.get {index}"),
+        Instruction::LocalSet(index) => format!("local.set {index}"),
+        Instruction::LocalTee(index) => format!("local.tee {index}"),
+        Instruction::MemorySize(_) => "memory.size".to_string(),
+        Instruction::MemoryGrow(_) => "memory.grow".to_string(),
+        Instruction::I32Load(m) => format!("i32.load{}", mem_arg(m, 2)),
+        Instruction::I32Load8_U(m) => format!("i32.load8_u{}", mem_arg(m, 0)),
+        Instruction::I32Load16_U(m) => format!("i32.load16_u{}", mem_arg(m, 1)),
+        Instruction::I64Load(m) => format!("i64.load{}", mem_arg(m, 3)),
+        Instruction::I32Store(m) => format!("i32.store{}", mem_arg(m, 2)),
+        Instruction::I32Store8(m) => format!("i32.store8{}", mem_arg(m, 0)),
+        Instruction::I32Store16(m) => format!("i32.store16{}", mem_arg(m, 1)),
+        Instruction::I64Store(m) => format!("i64.store{}", mem_arg(m, 3)),
+        Instruction::I32Const(value) => format!("i32.const {value}"),
+        Instruction::I64Const(value) => format!("i64.const {value}"),
+        Instruction::I32Eqz => "i32.eqz".to_string(),
+        Instruction::I32Eq => "i32.eq".to_string(),
+        Instruction::I32Ne => "i32.ne".to_string(),
+        Instruction::I32LtS => "i32.lt_s".to_string(),
+        Instruction::I32GtU => "i32.gt_u".to_string(),
+        Instruction::I32GeS => "i32.ge_s".to_string(),
+        Instruction::I32GeU => "i32.ge_u".to_string(),
+        Instruction::I32Add => "i32.add".to_string(),
+        Instruction::I32Sub => "i32.sub".to_string(),
+        Instruction::I32Mul => "i32.mul".to_string(),
+        Instruction::I32DivU => "i32.div_u".to_string(),
+        Instruction::I32RemU => "i32.rem_u".to_string(),
+        Instruction::I32And => "i32.and".to_string(),
+        Instruction::I32Shl => "i32.shl".to_string(),
+        Instruction::I32ShrU => "i32.shr_u".to_string(),
+        Instruction::I64Eqz => "i64.eqz".to_string(),
+        Instruction::I64Ne => "i64.ne".to_string(),
+        Instruction::I64Add => "i64.add".to_string(),
+        Instruction::I64Sub => "i64.sub".to_string(),
+        Instruction::I64Mul => "i64.mul".to_string(),
+        Instruction::I64ExtendI32U => "i64.extend_i32_u".to_string(),
+        Instruction::I64And => "i64.and".to_string(),
+        Instruction::I64Xor => "i64.xor".to_string(),
+        Instruction::I64Ctz => "i64.ctz".to_string(),
+        Instruction::I32WrapI64 => "i32.wrap_i64".to_string(),
+        other => unreachable!("{other:?} is never emitted"),
+    }
+}
+
+/// Module in text format, built alongside the binary sections
+#[derive(Default)]
+pub(crate) struct ModuleText {
+    types: Vec<String>,
+    imports: Vec<String>,
+    funcs: Vec<String>,
+    memory: String,
+    exports: Vec<String>,
+}
+
+impl ModuleText {
+    /// Add a function type
+    pub(crate) fn ty(&mut self, params: &[ValType], results: &[ValType]) {
+        let mut text = format!("(type (;{};) (func", self.types.len());
+        for (kind, types) in [("param", params), ("result", results)] {
+            if !types.is_empty() {
+                write!(text, " ({kind}").unwrap();
+                for ty in types {
+                    write!(text, " {}", val_type(ty)).unwrap();
+                }
+                text.push(')');
+            }
+        }
+        text.push_str("))");
+        self.types.push(text);
+    }
+
+    /// Add an imported function of a type
+    pub(crate) fn import(&mut self, module: &str, name: &str, ty: u32) {
+        let index = self.imports.len();
+        self.imports.push(format!(
+            "(import \"{module}\" \"{name}\" (func (;{index};) (type {ty})))"
+        ));
+    }
+
+    /// Add a function of a type with its body
+    pub(crate) fn func(&mut self, name: &str, ty: u32, body: &Body) {
+        let index = self.imports.len() + self.funcs.len();
+        self.funcs.push(format!(
+            "(func ${name} (;{index};) (type {ty})\n{}  )",
+            body.text()
+        ));
+    }
+
+    /// Set the memory limits in pages
+    pub(crate) fn memory(&mut self, minimum: u64, maximum: Option<u64>) {
+        self.memory = match maximum {
+            Some(maximum) => format!("(memory (;0;) {minimum} {maximum})"),
+            None => format!("(memory (;0;) {minimum})"),
+        };
+    }
+
+    /// Export a function or the memory by index
+    pub(crate) fn export(&mut self, name: &str, kind: &str, index: u32) {
+        self.exports
+            .push(format!("(export \"{name}\" ({kind} {index}))"));
+    }
+
+    /// Text of the whole module
+    pub(crate) fn finish(&self) -> String {
+        let mut text = String::from("(module\n");
+        for line in self
+            .types
+            .iter()
+            .chain(&self.imports)
+            .chain(&self.funcs)
+            .chain([&self.memory])
+            .chain(&self.exports)
+        {
+            writeln!(text, "  {line}").unwrap();
+        }
+        text.push_str(")\n");
+        text
+    }
+}
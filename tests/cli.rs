@@ -1,36 +1,492 @@
 use assert_cmd::prelude::*;
 use std::fs;
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+/// Path of a file in a directory under tests
+fn fixture(dir: &str, name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", dir, name]
+        .iter()
+        .collect()
+}
+
+/// Every .bf file in a directory under tests
+fn bf_fixtures(dir: &str) -> Vec<PathBuf> {
+    fs::read_dir(fixture(dir, ""))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().unwrap() == "bf")
+        .collect()
+}
+
+/// The CLI with its arguments
+fn brainfk(args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("brainfk-rs").unwrap();
+    cmd.args(args);
+    cmd
+}
+
+/// The CLI running a file with the LLVM backend
+fn run(bf_file: &Path, args: &[&str]) -> Command {
+    let mut cmd = brainfk(&["run", bf_file.to_str().unwrap(), "--backend", "llvm"]);
+    cmd.args(args);
+    cmd
+}
+
 #[test]
 fn compiler_sanity_check() -> Result<(), Box<dyn std::error::Error>> {
-    // List all .bf files in files
-    let mut tests_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    tests_dir.push("tests");
-    tests_dir.push("files");
-    let bf_files: Vec<PathBuf> = fs::read_dir(tests_dir)?
-        .map(|res| res.map(|e| e.path()))
-        .filter_map(|ele| match ele {
-            Ok(x) => Some(x),
-            Err(_) => None,
-        })
-        .filter(|x| x.extension().unwrap() == "bf")
-        .collect();
-
-    // Run all .bf files and compare outputs
-    for bf_file in bf_files {
-        let mut out_file = bf_file.clone();
-        out_file.set_extension("stdout");
-        let expected_stdout = fs::read_to_string(out_file).unwrap();
-        let mut cmd = Command::cargo_bin("brainfk-rs")?;
-        cmd.args([
-            "run",
-            bf_file.as_os_str().to_str().unwrap(),
-            "--backend",
-            "llvm",
+    // Run all .bf files at every offline optimization level and compare outputs
+    for bf_file in bf_fixtures("files") {
+        let expected_stdout = fs::read_to_string(bf_file.with_extension("stdout"))?;
+        // Programs reading input have a matching .stdin file
+        let in_file = bf_file.with_extension("stdin");
+        for opt_level in ["0", "1", "2"] {
+            let mut cmd = run(&bf_file, &["-O", opt_level]);
+            if in_file.exists() {
+                cmd.stdin(File::open(&in_file)?);
+            }
+            cmd.assert().success().stdout(expected_stdout.clone());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore = "downloads wasm-opt"]
+fn wasm_opt_level() -> Result<(), Box<dyn std::error::Error>> {
+    let bf_file = fixture("files", "hello.bf");
+    let out_file = std::env::temp_dir().join("brainfk-rs-wasm-opt-level.wasm");
+
+    let mut modules = vec![];
+    for opt_level in ["2", "3"] {
+        brainfk(&[
+            "generate-wasm",
+            bf_file.to_str().unwrap(),
+            out_file.to_str().unwrap(),
+            "--target",
+            "wasi",
+            "-O",
+            opt_level,
+        ])
+        .assert()
+        .success();
+        modules.push(fs::read(&out_file)?);
+        fs::remove_file(&out_file)?;
+    }
+
+    // -O3 also runs wasm-opt over the module
+    wasmparser::validate(&modules[1])?;
+    assert_ne!(modules[0], modules[1]);
+    Ok(())
+}
+
+#[test]
+fn cell_width_wraps() -> Result<(), Box<dyn std::error::Error>> {
+    // Prints 256 + 'A', which wraps to 'A' in 8 bit cells
+    let bf_file = fixture("cell_width", "wrap.bf");
+
+    let expected: [(&str, &[u8]); 4] = [
+        ("8", &[0x41]),
+        ("16", &[0x41, 0x01]),
+        ("32", &[0x41, 0x01, 0x00, 0x00]),
+        ("64", &[0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    ];
+    for (cell_width, expected_stdout) in expected {
+        run(&bf_file, &["--cell-width", cell_width])
+            .assert()
+            .success()
+            .stdout(expected_stdout);
+    }
+    Ok(())
+}
+
+#[test]
+fn eof_modes() -> Result<(), Box<dyn std::error::Error>> {
+    // Reads twice from a single byte of input
+    let bf_file = fixture("eof", "read_twice.bf");
+
+    let expected: [(&str, &[u8]); 3] = [
+        ("zero", b"a\0"),
+        ("minus-one", b"a\xff"),
+        ("unchanged", b"aa"),
+    ];
+    for (eof, expected_stdout) in expected {
+        let cmd = run(&bf_file, &["--cell-width", "8", "--eof", eof]);
+        assert_cmd::Command::from_std(cmd)
+            .write_stdin("a")
+            .assert()
+            .success()
+            .stdout(expected_stdout);
+    }
+    Ok(())
+}
+
+#[test]
+fn checked_tape_bounds() -> Result<(), Box<dyn std::error::Error>> {
+    // Reported the same at every optimization level
+    let growable: &[&str] = &["--max-pages", "4"];
+    let fixed: &[&str] = &["--tape", "fixed", "--tape-length", "10"];
+    let expected = [
+        ("underflow.bf", growable, "tape underflow at instruction 7"),
+        ("overflow.bf", growable, "tape overflow at instruction 4"),
+        ("left_right.bf", growable, "tape underflow at instruction 1"),
+        ("right_run.bf", fixed, "tape overflow at instruction 10"),
+    ];
+    for (bf_file, tape_args, expected_stderr) in expected {
+        for opt_level in ["0", "1", "2"] {
+            let mut cmd = run(
+                &fixture("checked", bf_file),
+                &["-O", opt_level, "--checked"],
+            );
+            cmd.args(tape_args);
+            cmd.assert()
+                .failure()
+                .stderr(predicates::str::contains(expected_stderr));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let out_file = std::env::temp_dir().join("brainfk-rs-exit-codes.wasm");
+
+    // Each failing stage has its own exit code
+    let expected = [
+        (fixture("errors", "unmatched_close.bf"), "0", 3),
+        (fixture("files", "hello.bf"), "30000", 4),
+    ];
+    for (bf_file, tape_start, code) in expected {
+        brainfk(&[
+            "generate-wasm",
+            bf_file.to_str().unwrap(),
+            out_file.to_str().unwrap(),
+            "--target",
+            "wasi",
+            "--tape-start",
+            tape_start,
+        ])
+        .assert()
+        .failure()
+        .code(code);
+    }
+
+    // Runtime traps
+    run(&fixture("checked", "overflow.bf"), &["--tape", "fixed"])
+        .assert()
+        .failure()
+        .code(9);
+    Ok(())
+}
+
+#[test]
+fn unmatched_brackets() -> Result<(), Box<dyn std::error::Error>> {
+    let out_file = std::env::temp_dir().join("brainfk-rs-unmatched-brackets.wasm");
+
+    // Caret under the offending bracket
+    let expected = [
+        (
+            "unmatched_close.bf",
+            "Unmatched ']' at line 2, column 4\n  |\n2 | <-]]\n  |    ^\n",
+        ),
+        (
+            "unmatched_open.bf",
+            "Unmatched '[' at line 1, column 4\n  |\n1 | +++[>++\n  |    ^\n",
+        ),
+    ];
+    for (bf_file, expected_stderr) in expected {
+        brainfk(&[
+            "generate-wasm",
+            fixture("errors", bf_file).to_str().unwrap(),
+            out_file.to_str().unwrap(),
+            "--target",
+            "wasi",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(expected_stderr));
+    }
+    Ok(())
+}
+
+#[test]
+fn tape_layout() -> Result<(), Box<dyn std::error::Error>> {
+    // Moves left of the starting cell first
+    let left_first = fixture("tape", "left_first.bf");
+    run(
+        &left_first,
+        &["--cell-width", "8", "--checked", "--tape-start", "1"],
+    )
+    .assert()
+    .success()
+    .stdout("A");
+
+    // Moves right until it runs off the tape
+    let overflow = fixture("checked", "overflow.bf");
+    run(
+        &overflow,
+        &["-O", "0", "--tape", "fixed", "--tape-length", "10"],
+    )
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("tape overflow at instruction 4"));
+    Ok(())
+}
+
+#[test]
+fn wrapping_tape() -> Result<(), Box<dyn std::error::Error>> {
+    // Moves left of the first cell onto the last one
+    let bf_file = fixture("tape", "left_first.bf");
+
+    // Power of two lengths wrap by masking
+    for tape_length in ["16", "10"] {
+        for opt_level in ["0", "2"] {
+            let args = [
+                "-O",
+                opt_level,
+                "--cell-width",
+                "8",
+                "--tape",
+                "wrap",
+                "--tape-length",
+                tape_length,
+            ];
+            run(&bf_file, &args).assert().success().stdout("A");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn wide_scan() -> Result<(), Box<dyn std::error::Error>> {
+    // Scans right to a zero cell eight cells at a time, then one at a time
+    // near the end of the tape
+    let bf_file = fixture("scan", "wide.bf");
+
+    let expected = [("21", true, "AB"), ("20", false, "A")];
+    for (tape_length, success, expected_stdout) in expected {
+        let args = [
+            "--cell-width",
+            "8",
+            "--checked",
+            "--tape",
+            "fixed",
+            "--tape-length",
+            tape_length,
+        ];
+        let assert = run(&bf_file, &args).assert().stdout(expected_stdout);
+        if success {
+            assert.success();
+        } else {
+            assert.failure().stderr(predicates::str::contains(
+                "tape overflow at instruction 128",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn browser_target() -> Result<(), Box<dyn std::error::Error>> {
+    let bf_file = fixture("files", "rot13.bf");
+    let out_file = std::env::temp_dir().join("brainfk-rs-browser-target.wasm");
+    let generate = |args: &[&str]| {
+        let mut cmd = brainfk(&[
+            "generate-wasm",
+            bf_file.to_str().unwrap(),
+            out_file.to_str().unwrap(),
+            "--glue",
         ]);
-        cmd.assert().success().stdout(expected_stdout);
+        cmd.args(args);
+        cmd
+    };
+
+    generate(&["--target", "browser"]).assert().success();
+
+    // Glue loads the wasm file next to it
+    let js_file = out_file.with_extension("js");
+    let ts_file = out_file.with_extension("d.ts");
+    let js = fs::read_to_string(&js_file)?;
+    let ts = fs::read_to_string(&ts_file)?;
+    fs::remove_file(&js_file)?;
+    fs::remove_file(&ts_file)?;
+    assert!(js.contains("./brainfk-rs-browser-target.wasm"));
+    assert!(js.contains("export async function run("));
+    assert!(ts.contains("export function run(input?: Uint8Array): Promise<Uint8Array>;"));
+
+    // Imports the browser host functions instead of WASI
+    let wasm_bytes = fs::read(&out_file)?;
+    fs::remove_file(&out_file)?;
+    wasmparser::validate(&wasm_bytes)?;
+    let contains = |s: &[u8]| wasm_bytes.windows(s.len()).any(|w| w == s);
+    assert!(contains(b"env") && contains(b"read") && contains(b"write"));
+    assert!(!contains(b"wasi_unstable"));
+
+    // Glue only loads browser targeted wasm files, other uses are usage errors
+    let misuses = [
+        ["--target", "wasi", "--format", "wasm"],
+        ["--target", "browser", "--format", "wat"],
+    ];
+    for args in misuses {
+        generate(&args).assert().failure().code(2);
+    }
+    Ok(())
+}
+
+#[test]
+fn wat_format() -> Result<(), Box<dyn std::error::Error>> {
+    let bf_file = fixture("files", "rot13.bf");
+    let out_file = std::env::temp_dir().join("brainfk-rs-wat-format.wat");
+
+    brainfk(&[
+        "generate-wasm",
+        bf_file.to_str().unwrap(),
+        out_file.to_str().unwrap(),
+        "--target",
+        "wasi",
+        "--format",
+        "wat",
+    ])
+    .assert()
+    .success();
+
+    // Text of the same module with blocks mapped back to the source
+    let wat = fs::read_to_string(&out_file)?;
+    fs::remove_file(&out_file)?;
+    assert!(wat.starts_with("(module"));
+    assert!(wat.contains("(import \"wasi_unstable\" \"fd_write\""));
+    assert!(wat.contains("(export \"_start\" (func 2))"));
+    assert!(wat.contains("i32.const 2048"));
+    assert!(wat.contains(";; loop at instruction 4"));
+    assert!(wat.contains(";; output at instruction"));
+    Ok(())
+}
+
+#[test]
+fn compile_binary() -> Result<(), Box<dyn std::error::Error>> {
+    let bf_file = fixture("files", "hello.bf");
+    let expected_stdout = fs::read_to_string(bf_file.with_extension("stdout"))?;
+    let out_file = std::env::temp_dir().join("brainfk-rs-compile-binary");
+
+    brainfk(&[
+        "compile-binary",
+        bf_file.to_str().unwrap(),
+        out_file.to_str().unwrap(),
+        "--backend",
+        "cranelift",
+    ])
+    .assert()
+    .success();
+
+    // Runs on its own, ignoring arguments
+    let output = Command::new(&out_file).arg("--help").output()?;
+    fs::remove_file(&out_file)?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, expected_stdout);
+    Ok(())
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn native_backend() -> Result<(), Box<dyn std::error::Error>> {
+    let out_file = std::env::temp_dir().join("brainfk-rs-native-backend");
+
+    // Same outputs as through wasm, on a tape long enough for every program
+    for name in ["hello.bf", "rot13.bf", "squares.bf", "grow.bf"] {
+        let bf_file = fixture("files", name);
+        let in_file = bf_file.with_extension("stdin");
+        let expected_stdout = fs::read_to_string(bf_file.with_extension("stdout"))?;
+        for opt_level in ["0", "2"] {
+            brainfk(&[
+                "compile-native",
+                bf_file.to_str().unwrap(),
+                out_file.to_str().unwrap(),
+                "-O",
+                opt_level,
+                "--tape-length",
+                "70000",
+            ])
+            .assert()
+            .success();
+
+            let mut cmd = Command::new(&out_file);
+            if in_file.exists() {
+                cmd.stdin(File::open(&in_file)?);
+            }
+            cmd.assert().success().stdout(expected_stdout.clone());
+        }
+    }
+    fs::remove_file(&out_file)?;
+    Ok(())
+}
+
+#[test]
+fn generate_c() -> Result<(), Box<dyn std::error::Error>> {
+    let bf_file = fixture("files", "rot13.bf");
+    let out_file = std::env::temp_dir().join("brainfk-rs-generate-c.c");
+
+    brainfk(&[
+        "generate-c",
+        bf_file.to_str().unwrap(),
+        out_file.to_str().unwrap(),
+        "--cell-width",
+        "8",
+        "--eof",
+        "zero",
+        "--tape",
+        "wrap",
+    ])
+    .assert()
+    .success();
+
+    // Settings carry over to the C source
+    let source = fs::read_to_string(&out_file)?;
+    fs::remove_file(&out_file)?;
+    assert!(source.contains("typedef uint8_t cell;"));
+    assert!(source.contains("i %= length;"));
+    assert!(source.contains("tape[h] = 0;"));
+    assert!(source.contains("int main(void) {"));
+    Ok(())
+}
+
+#[test]
+fn c_backend() -> Result<(), Box<dyn std::error::Error>> {
+    // Needs a C compiler on the path
+    if Command::new("cc").arg("--version").output().is_err() {
+        return Ok(());
+    }
+    let c_file = std::env::temp_dir().join("brainfk-rs-c-backend.c");
+    let out_file = std::env::temp_dir().join("brainfk-rs-c-backend");
+
+    // Same outputs as through wasm, -O0 only differs from -O1 in not folding
+    // runs and takes long on mandelbrot
+    for bf_file in bf_fixtures("files") {
+        let in_file = bf_file.with_extension("stdin");
+        let expected_stdout = fs::read_to_string(bf_file.with_extension("stdout"))?;
+        for opt_level in ["1", "2"] {
+            brainfk(&[
+                "generate-c",
+                bf_file.to_str().unwrap(),
+                c_file.to_str().unwrap(),
+                "-O",
+                opt_level,
+            ])
+            .assert()
+            .success();
+
+            let mut cmd = Command::new("cc");
+            cmd.args(["-O1", "-o"]).arg(&out_file).arg(&c_file);
+            cmd.assert().success();
+
+            let mut cmd = Command::new(&out_file);
+            if in_file.exists() {
+                cmd.stdin(File::open(&in_file)?);
+            }
+            cmd.assert().success().stdout(expected_stdout.clone());
+        }
     }
+    fs::remove_file(&c_file)?;
+    fs::remove_file(&out_file)?;
     Ok(())
 }